@@ -0,0 +1,222 @@
+use crate::v8chart::{DataSet, Record};
+
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use std::str::FromStr;
+
+/// How prices and volumes are adjusted for corporate actions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjustment {
+    /// as yahoo quotes them, that is adjusted for splits but not for dividends
+    None,
+    /// back-adjusted for splits and dividends so that close equals adjclose
+    All,
+}
+
+impl FromStr for Adjustment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Adjustment::None),
+            "all" => Ok(Adjustment::All),
+            _ => Err(format!("unknown adjustment {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Adjustment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Adjustment::None => write!(f, "none"),
+            Adjustment::All => write!(f, "all"),
+        }
+    }
+}
+
+impl Record {
+    /// multiply open, high, low and close by `ratio` and divide the volume by it
+    pub fn scale(&mut self, ratio: f64) {
+        for price in [&mut self.open, &mut self.high, &mut self.low, &mut self.close].iter_mut() {
+            if let Some(p) = price.as_mut() {
+                *p *= ratio;
+            }
+        }
+        self.volume = self.volume.map(|v| (v as f64 / ratio).round() as u64);
+    }
+
+    /// the ratio that back-adjusts this bar, `None` if there is nothing to do or it cannot be
+    /// worked out. Dividing by [Record::cum_split_factor] again would adjust yahoo's prices twice
+    fn adjustment_ratio(&self, adjustment: Adjustment) -> Option<f64> {
+        match adjustment {
+            Adjustment::None => None,
+            Adjustment::All => match (self.adjclose, self.close) {
+                (Some(adj), Some(close)) if close != 0.0 => Some(adj / close),
+                // intraday bars have no adjclose
+                _ => None,
+            },
+        }
+    }
+}
+
+impl DataSet {
+    /// Back-adjust OHLC with the adjustment ratio of every bar and volume inversely, so that the
+    /// whole series is comparable with the latest bar. Bars without adjclose are left as they are
+    /// with `All`. Applying it twice adjusts twice.
+    pub fn adjust(&mut self, adjustment: Adjustment) {
+        for r in self.records.iter_mut() {
+            if let Some(ratio) = r.adjustment_ratio(adjustment) {
+                r.scale(ratio);
+            }
+        }
+    }
+}
+
+impl DataSet {
+    /// CRSP-style cumulative adjustment factor of every bar. A cash distribution `D` (dividend plus
+    /// capital gain) on a bar multiplies every earlier bar by `1 - D / C` where `C` is the close of
+    /// the bar before it. With `splits`, a split additionally divides every earlier bar by its
+    /// factor, which is only right when the closes are not split-adjusted already.
+    pub fn adjustment_factors(&self, splits: bool) -> Vec<f64> {
+        let mut factors = vec![1.0; self.records.len()];
+        let mut cum = 1.0;
+        for (i, r) in self.records.iter().enumerate().rev() {
+            factors[i] = cum;
+            let cash = r.dividend.unwrap_or(0.0) + r.capital_gain.unwrap_or(0.0);
+            if cash > 0.0 {
+                match self.records[..i].iter().rev().find_map(|p| p.close) {
+                    Some(prev) if prev > cash => cum *= 1.0 - cash / prev,
+                    _ => warn!("cannot adjust for a distribution of {} at {}", cash, r.timestamp),
+                }
+            }
+            if splits {
+                if let Some(factor) = r.split_factor {
+                    cum /= factor;
+                }
+            }
+        }
+        factors
+    }
+
+    /// rebuild adjclose from the closes and the parsed events, see [DataSet::adjustment_factors]
+    pub fn recompute_adjclose(&self, splits: bool) -> Vec<Option<f64>> {
+        self.records
+            .iter()
+            .zip(self.adjustment_factors(splits))
+            .map(|(r, f)| r.close.map(|c| c * f))
+            .collect()
+    }
+
+    /// bars whose local adjclose differs from yahoo's by more than a relative `tolerance`
+    pub fn verify_adjclose(&self, splits: bool, tolerance: f64) -> Vec<Mismatch> {
+        self.records
+            .iter()
+            .zip(self.recompute_adjclose(splits))
+            .filter_map(|(r, local)| {
+                let (yahoo, local) = (r.adjclose?, local?);
+                let rel_diff = (local - yahoo).abs() / yahoo.abs().max(f64::EPSILON);
+                if rel_diff > tolerance {
+                    Some(Mismatch {
+                        symbol: self.meta.symbol.clone(),
+                        timestamp: r.timestamp,
+                        close: r.close,
+                        adjclose: yahoo,
+                        local_adjclose: local,
+                        rel_diff,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// A bar where the recomputed adjclose disagrees with yahoo
+#[derive(Serialize, Debug)]
+pub struct Mismatch {
+    pub symbol: String,
+    pub timestamp: DateTime<FixedOffset>,
+    pub close: Option<f64>,
+    pub adjclose: f64,
+    pub local_adjclose: f64,
+    pub rel_diff: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_parse_adjustment() {
+        assert_eq!("all".parse::<Adjustment>(), Ok(Adjustment::All));
+        // yahoo's prices are split-adjusted already, there is no splits-only adjustment
+        assert!("splits".parse::<Adjustment>().is_err());
+        assert!("both".parse::<Adjustment>().is_err());
+    }
+
+    #[test]
+    fn test_adjust_none() {
+        // yahoo's prices and volumes are split-adjusted already and stay as they are
+        let raw = load("assets/AAPL_init_20210126.json");
+        let mut ds = load("assets/AAPL_init_20210126.json");
+        ds.adjust(Adjustment::None);
+        assert_eq!(raw.records[0].cum_split_factor, 224.0);
+        for (a, r) in ds.records.iter().zip(raw.records.iter()) {
+            assert_eq!((a.open, a.high, a.low, a.close), (r.open, r.high, r.low, r.close));
+            assert_eq!(a.volume, r.volume);
+        }
+    }
+
+    #[test]
+    fn test_adjust_all() {
+        let mut ds = load("assets/VFIAX_20201214_20201218.json");
+        ds.adjust(Adjustment::All);
+        for r in ds.records.iter() {
+            assert!((r.close.unwrap() - r.adjclose.unwrap()).abs() < 1e-9);
+            assert!(r.high.unwrap() >= r.low.unwrap());
+        }
+        let mut none = load("assets/VFIAX_20201214_20201218.json");
+        none.adjust(Adjustment::None);
+        assert_eq!(none.records[0].close, Some(337.61));
+    }
+
+    #[test]
+    fn test_recompute_adjclose() {
+        let ds = load("assets/AAPL_init_20210126.json");
+        assert!(ds.verify_adjclose(false, 1e-5).is_empty());
+        // the closes are split-adjusted already so adjusting for splits again is way off
+        assert!(!ds.verify_adjclose(true, 1e-5).is_empty());
+
+        let ds = load("assets/VFIAX_20201214_20201218.json");
+        let local = ds.recompute_adjclose(false);
+        assert!((local[0].unwrap() - 336.1229).abs() < 1e-4);
+        assert_eq!(local[2], Some(340.23));
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        // yahoo's adjclose before the distribution is about 0.8% below the CRSP-style factor
+        let ds = load("assets/VFIAX_20201214_20201218.json");
+        let mismatches = ds.verify_adjclose(false, 1e-4);
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].symbol, "VFIAX");
+        assert_eq!(mismatches[0].adjclose, 333.4);
+        assert_eq!(mismatches[1].adjclose, 337.6);
+        assert!(mismatches.iter().all(|m| m.rel_diff > 0.008 && m.rel_diff < 0.0083));
+        assert!(ds.verify_adjclose(false, 0.01).is_empty());
+    }
+
+    #[test]
+    fn test_adjust_all_intraday() {
+        let mut ds = load("assets/AAPL_5m_20210125.json");
+        ds.adjust(Adjustment::All);
+        assert_eq!(ds.records[0].close, Some(142.42999267578125));
+    }
+}
@@ -0,0 +1,327 @@
+use crate::v8chart::DataSet;
+
+use arrow::{
+    array::{ArrayRef, DictionaryArray, Float64Array, StringArray, TimestampMillisecondArray, UInt64Array},
+    compute::concat_batches,
+    datatypes::{DataType, Field, Int32Type, Schema, SchemaRef, TimeUnit},
+    error::ArrowError,
+    ipc::writer::FileWriter,
+    record_batch::RecordBatch,
+};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, GzipLevel, ZstdLevel},
+    file::properties::WriterProperties,
+    format::KeyValue,
+};
+use std::{error::Error, fs::File, io::Write, path::Path, str::FromStr, sync::Arc};
+
+/// key of the file metadata that holds the JSON array of the [crate::v8chart::V8Meta] of every
+/// dataset in a parquet or arrow file
+pub const META_KEY: &str = "yfinance.v8meta";
+
+/// A parquet compression codec
+#[derive(Debug, Clone, Copy)]
+pub struct Codec(pub Compression);
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec(Compression::UNCOMPRESSED)),
+            "snappy" => Ok(Codec(Compression::SNAPPY)),
+            "gzip" => Ok(Codec(Compression::GZIP(GzipLevel::default()))),
+            "zstd" => Ok(Codec(Compression::ZSTD(ZstdLevel::default()))),
+            "lz4" => Ok(Codec(Compression::LZ4_RAW)),
+            _ => Err(format!("unknown compression {}", s)),
+        }
+    }
+}
+
+/// The timezone the timestamps of a dataset are tagged with: the exchange timezone when yahoo
+/// tells it, its fixed offset such as `-05:00` otherwise
+pub fn timezone(ds: &DataSet) -> String {
+    match ds.meta.exchange_timezone_name.as_ref() {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => ds.meta.offset().to_string(),
+    }
+}
+
+/// The columns of [crate::v8chart::write_to_csv] with the symbol in front. This schema is shared
+/// by the parquet and arrow outputs and only ever grows at the end:
+///
+/// | column             | type                               | nullable |
+/// |--------------------|------------------------------------|----------|
+/// | `symbol`           | dictionary of int32 to utf8        | no       |
+/// | `timestamp`        | timestamp in ms tagged `timezone`  | no       |
+/// | `volume`           | uint64                             | yes      |
+/// | `high`             | float64                            | yes      |
+/// | `low`              | float64                            | yes      |
+/// | `open`             | float64                            | yes      |
+/// | `close`            | float64                            | yes      |
+/// | `adjclose`         | float64                            | yes      |
+/// | `split`            | utf8, the ratio such as `4:1`      | yes      |
+/// | `split_factor`     | float64                            | yes      |
+/// | `dividend`         | float64                            | yes      |
+/// | `capital_gain`     | float64                            | yes      |
+/// | `cum_split_factor` | float64                            | no       |
+pub fn schema(timezone: &str) -> Schema {
+    let price = |name: &str| Field::new(name, DataType::Float64, true);
+    Schema::new(vec![
+        Field::new(
+            "symbol",
+            DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+            false,
+        ),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some(timezone.into())),
+            false,
+        ),
+        Field::new("volume", DataType::UInt64, true),
+        price("high"),
+        price("low"),
+        price("open"),
+        price("close"),
+        price("adjclose"),
+        Field::new("split", DataType::Utf8, true),
+        price("split_factor"),
+        price("dividend"),
+        price("capital_gain"),
+        Field::new("cum_split_factor", DataType::Float64, false),
+    ])
+}
+
+/// the columns of a dataset in the order of [schema], timestamps tagged with `timezone`
+pub fn record_batch(ds: &DataSet, timezone: &str) -> Result<RecordBatch, ArrowError> {
+    let records = &ds.records;
+    let float = |f: fn(&crate::v8chart::Record) -> Option<f64>| -> ArrayRef {
+        Arc::new(records.iter().map(f).collect::<Float64Array>())
+    };
+    let symbol: DictionaryArray<Int32Type> = records.iter().map(|_| ds.meta.symbol.as_str()).collect();
+    let timestamp = records
+        .iter()
+        .map(|r| r.timestamp.timestamp_millis())
+        .collect::<Vec<i64>>();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(symbol),
+        Arc::new(TimestampMillisecondArray::from(timestamp).with_timezone(timezone)),
+        Arc::new(records.iter().map(|r| r.volume).collect::<UInt64Array>()),
+        float(|r| r.high),
+        float(|r| r.low),
+        float(|r| r.open),
+        float(|r| r.close),
+        float(|r| r.adjclose),
+        Arc::new(records.iter().map(|r| r.split.as_deref()).collect::<StringArray>()),
+        float(|r| r.split_factor),
+        float(|r| r.dividend),
+        float(|r| r.capital_gain),
+        float(|r| Some(r.cum_split_factor)),
+    ];
+    RecordBatch::try_new(Arc::new(schema(timezone)), columns)
+}
+
+impl DataSet {
+    /// the dataset as an arrow record batch of [schema], timestamps tagged with [timezone]
+    pub fn to_record_batch(&self) -> Result<RecordBatch, ArrowError> {
+        record_batch(self, &timezone(self))
+    }
+}
+
+/// Streams datasets into one parquet file with a row group per dataset. The metas are written to
+/// the file metadata under [META_KEY] on [ParquetSink::close]
+pub struct ParquetSink<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    timezone: String,
+    metas: Vec<serde_json::Value>,
+}
+
+impl<W: Write + Send> ParquetSink<W> {
+    /// all the timestamps in the file are tagged with `timezone`, use `UTC` when the datasets come
+    /// from different exchanges
+    pub fn try_new(writer: W, timezone: &str, codec: Codec) -> Result<Self, Box<dyn Error>> {
+        let schema: SchemaRef = Arc::new(schema(timezone));
+        let props = WriterProperties::builder().set_compression(codec.0).build();
+        Ok(ParquetSink {
+            writer: ArrowWriter::try_new(writer, schema, Some(props))?,
+            timezone: timezone.to_string(),
+            metas: Vec::new(),
+        })
+    }
+
+    pub fn write(&mut self, ds: &DataSet) -> Result<(), Box<dyn Error>> {
+        self.writer.write(&record_batch(ds, &self.timezone)?)?;
+        self.writer.flush()?;
+        self.metas.push(serde_json::to_value(&ds.meta)?);
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(), Box<dyn Error>> {
+        let metas = serde_json::to_string(&self.metas)?;
+        self.writer
+            .append_key_value_metadata(KeyValue::new(META_KEY.to_string(), metas));
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+/// write a dataset to a parquet file at path, the parquet counterpart of
+/// [crate::v8chart::write_to_csv]
+pub fn write_to_parquet<P: AsRef<Path>>(ds: &DataSet, path: P, codec: Codec) -> Result<(), Box<dyn Error>> {
+    let mut sink = ParquetSink::try_new(File::create(path)?, &timezone(ds), codec)?;
+    sink.write(ds)?;
+    sink.close()
+}
+
+/// Collects datasets into one arrow IPC file, also known as Feather v2, that can be memory-mapped
+/// by the readers. The IPC file format cannot replace a dictionary once written, so the batches
+/// are concatenated into one with a shared symbol dictionary on [FeatherSink::close]
+pub struct FeatherSink<W: Write> {
+    writer: W,
+    timezone: String,
+    batches: Vec<RecordBatch>,
+    metas: Vec<serde_json::Value>,
+}
+
+impl<W: Write> FeatherSink<W> {
+    /// all the timestamps in the file are tagged with `timezone`, use `UTC` when the datasets come
+    /// from different exchanges
+    pub fn new(writer: W, timezone: &str) -> Self {
+        FeatherSink {
+            writer,
+            timezone: timezone.to_string(),
+            batches: Vec::new(),
+            metas: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, ds: &DataSet) -> Result<(), Box<dyn Error>> {
+        self.batches.push(record_batch(ds, &self.timezone)?);
+        self.metas.push(serde_json::to_value(&ds.meta)?);
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<(), Box<dyn Error>> {
+        let schema: SchemaRef = Arc::new(schema(&self.timezone));
+        let batch = concat_batches(&schema, &self.batches)?;
+        let mut writer = FileWriter::try_new(self.writer, &schema)?;
+        writer.write_metadata(META_KEY, serde_json::to_string(&self.metas)?);
+        writer.write(&batch)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// write a dataset to an arrow IPC file at path, the arrow counterpart of
+/// [crate::v8chart::write_to_csv]
+pub fn write_to_feather<P: AsRef<Path>>(ds: &DataSet, path: P) -> Result<(), Box<dyn Error>> {
+    let mut sink = FeatherSink::new(File::create(path)?, &timezone(ds));
+    sink.write(ds)?;
+    sink.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::{Float64Type, UInt64Type};
+    use arrow::ipc::reader::FileReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rand::{distributions::Alphanumeric, thread_rng, Rng};
+    use std::fs::remove_file;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        let prefix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+        std::env::temp_dir().join(prefix)
+    }
+
+    #[test]
+    fn test_write_parquet() {
+        let ds = load("assets/ES=F_20210104_20210106.json");
+        let path = temp_path();
+        write_to_parquet(&ds, &path, "zstd".parse().unwrap()).unwrap();
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        let kv = builder.metadata().file_metadata().key_value_metadata().unwrap();
+        let metas = kv.iter().find(|kv| kv.key == META_KEY).unwrap().value.as_ref().unwrap();
+        let metas: serde_json::Value = serde_json::from_str(metas).unwrap();
+        assert_eq!(metas[0]["symbol"], "ES=F");
+        assert_eq!(
+            builder.schema().field_with_name("timestamp").unwrap().data_type(),
+            &DataType::Timestamp(TimeUnit::Millisecond, Some("America/New_York".into()))
+        );
+        let batch = builder.build().unwrap().next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 3);
+        let close = batch.column_by_name("close").unwrap().as_primitive::<Float64Type>();
+        assert_eq!(close.value(0), 3690.0);
+        assert!(close.is_null(2));
+        let volume = batch.column_by_name("volume").unwrap().as_primitive::<UInt64Type>();
+        assert_eq!(Some(volume.value(0)), ds.records[0].volume);
+        let symbol = batch.column_by_name("symbol").unwrap().as_dictionary::<Int32Type>();
+        assert_eq!(symbol.values().len(), 1);
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_parquet_sink() {
+        let path = temp_path();
+        let mut sink = ParquetSink::try_new(File::create(&path).unwrap(), "UTC", "snappy".parse().unwrap()).unwrap();
+        sink.write(&load("assets/^GSPC_20210104_20210108.json")).unwrap();
+        sink.write(&load("assets/BTC-USD_20210104_20210106.json")).unwrap();
+        sink.close().unwrap();
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(builder.metadata().num_row_groups(), 2);
+        let rows: usize = builder.build().unwrap().map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 6);
+        assert!("brotli".parse::<Codec>().is_err());
+        let _ = remove_file(path);
+    }
+
+    #[test]
+    fn test_record_batch() {
+        let ds = load("assets/AAPL_init_20210126.json");
+        let batch = ds.to_record_batch().unwrap();
+        assert_eq!(batch.num_rows(), ds.records.len());
+        let schema = batch.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.serialize(&ds.records[0]).unwrap();
+        let csv = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        let header: Vec<&str> = csv.lines().next().unwrap().split(',').collect();
+        assert_eq!(names[0], "symbol");
+        assert_eq!(names[1..], header[..]);
+        let splits = batch.column_by_name("split").unwrap().as_string::<i32>();
+        assert_eq!(splits.iter().flatten().count(), 5);
+    }
+
+    #[test]
+    fn test_feather_sink() {
+        let path = temp_path();
+        let mut sink = FeatherSink::new(File::create(&path).unwrap(), "UTC");
+        sink.write(&load("assets/^GSPC_20210104_20210108.json")).unwrap();
+        sink.write(&load("assets/BTC-USD_20210104_20210106.json")).unwrap();
+        sink.close().unwrap();
+
+        let reader = FileReader::try_new(File::open(&path).unwrap(), None).unwrap();
+        let metas: serde_json::Value = serde_json::from_str(&reader.custom_metadata()[META_KEY]).unwrap();
+        assert_eq!(metas[1]["symbol"], "BTC-USD");
+        let batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 6);
+        let symbol = batches[0].column(0).as_dictionary::<Int32Type>();
+        assert_eq!(symbol.values().len(), 2);
+        let _ = remove_file(path);
+    }
+}
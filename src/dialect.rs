@@ -0,0 +1,573 @@
+use crate::options::CsvArgs;
+use crate::panel::is_intraday;
+use crate::resample::Local;
+use crate::v8chart::{DataSet, Record};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, FixedOffset, NaiveDate, NaiveDateTime, SecondsFormat,
+};
+use csv::{QuoteStyle, ReaderBuilder, Writer, WriterBuilder};
+use std::{
+    error::Error,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+/// Every column of a csv output in the default order, the fields of [Record]
+pub const COLUMNS: [&str; 12] = [
+    "timestamp",
+    "volume",
+    "high",
+    "low",
+    "open",
+    "close",
+    "adjclose",
+    "split",
+    "split_factor",
+    "dividend",
+    "capital_gain",
+    "cum_split_factor",
+];
+
+/// The columns in front of every record of a combined csv, see [crate::v8chart::Tags]
+const TAGS: [&str; 4] = ["symbol", "exchange", "currency", "interval"];
+
+/// A field delimiter, a single ASCII character or `tab`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delimiter(pub u8);
+
+impl FromStr for Delimiter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tab" | "\\t" | "\t" => Ok(Delimiter(b'\t')),
+            _ if s.len() == 1 && s.is_ascii() => Ok(Delimiter(s.as_bytes()[0])),
+            _ => Err(format!("delimiter {} is not a single ascii character", s)),
+        }
+    }
+}
+
+/// When to quote a field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quoting {
+    /// only when it contains the delimiter, a quote or a line break
+    Necessary,
+    Always,
+    Never,
+    NonNumeric,
+}
+
+impl FromStr for Quoting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "necessary" => Ok(Quoting::Necessary),
+            "always" => Ok(Quoting::Always),
+            "never" => Ok(Quoting::Never),
+            "non-numeric" => Ok(Quoting::NonNumeric),
+            _ => Err(format!("unknown quoting {}", s)),
+        }
+    }
+}
+
+impl From<Quoting> for QuoteStyle {
+    fn from(quoting: Quoting) -> Self {
+        match quoting {
+            Quoting::Necessary => QuoteStyle::Necessary,
+            Quoting::Always => QuoteStyle::Always,
+            Quoting::Never => QuoteStyle::Never,
+            Quoting::NonNumeric => QuoteStyle::NonNumeric,
+        }
+    }
+}
+
+/// A comma separated selection of [COLUMNS] in the order they are written
+#[derive(Debug, Clone, PartialEq)]
+pub struct Columns(pub Vec<&'static str>);
+
+impl FromStr for Columns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|name| {
+                let name = name.trim();
+                COLUMNS
+                    .iter()
+                    .find(|c| **c == name)
+                    .copied()
+                    .ok_or_else(|| format!("unknown column {}, expecting some of {}", name, COLUMNS.join(",")))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Columns)
+    }
+}
+
+/// How a timestamp is written
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimestampFormat {
+    /// RFC 3339 with the exchange offset, `Z` for UTC
+    Rfc3339,
+    /// seconds since the epoch
+    Epoch,
+    /// milliseconds since the epoch
+    EpochMs,
+    /// the exchange-local date of daily and longer bars, intraday bars fall back to RFC 3339
+    Date,
+    /// a strftime pattern applied in exchange-local time, DST aware when the timezone is known
+    Strftime(String),
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch" => Ok(TimestampFormat::Epoch),
+            "epoch-ms" => Ok(TimestampFormat::EpochMs),
+            "date" => Ok(TimestampFormat::Date),
+            _ if s.contains('%') => {
+                if StrftimeItems::new(s).any(|item| item == Item::Error) {
+                    Err(format!("invalid strftime pattern {}", s))
+                } else {
+                    Ok(TimestampFormat::Strftime(s.to_string()))
+                }
+            }
+            _ => Err(format!(
+                "unknown timestamp format {}, expecting rfc3339, epoch, epoch-ms, date or a strftime pattern",
+                s
+            )),
+        }
+    }
+}
+
+/// a float as the csv serializer writes it, the shortest representation that round-trips with
+/// `.0` on whole numbers
+fn float(v: f64) -> String {
+    if v.is_finite() {
+        serde_json::to_string(&v).unwrap_or_default()
+    } else {
+        v.to_string()
+    }
+}
+
+/// The cells of the records of one dataset in a dialect
+struct Cells<'a> {
+    args: &'a CsvArgs,
+    local: Local,
+    date_only: bool,
+    decimals: Option<usize>,
+}
+
+impl<'a> Cells<'a> {
+    fn new(args: &'a CsvArgs, ds: &DataSet) -> Cells<'a> {
+        let date_only = args.timestamp_format == TimestampFormat::Date && !is_intraday(ds);
+        if args.timestamp_format == TimestampFormat::Date && !date_only {
+            warn!(
+                "writing rfc3339 timestamps instead of dates for {} as its bars are intraday",
+                ds.meta.symbol
+            );
+        }
+        let decimals = if args.round {
+            ds.meta.price_hint.map(|h| h.max(0.0) as usize)
+        } else {
+            None
+        };
+        Cells {
+            args,
+            local: Local::new(ds),
+            date_only,
+            decimals,
+        }
+    }
+
+    fn timestamp(&self, r: &Record) -> String {
+        match &self.args.timestamp_format {
+            TimestampFormat::Epoch => r.timestamp.timestamp().to_string(),
+            TimestampFormat::EpochMs => r.timestamp.timestamp_millis().to_string(),
+            TimestampFormat::Date if self.date_only => self.local.to_local(&r.timestamp.naive_utc()).date().to_string(),
+            TimestampFormat::Strftime(pattern) => {
+                // in the offset of the day rather than the one of the meta, as it is parsed back
+                let utc = r.timestamp.naive_utc();
+                let offset = (self.local.to_local(&utc) - utc).num_seconds() as i32;
+                match FixedOffset::east_opt(offset) {
+                    Some(offset) => DateTime::<FixedOffset>::from_naive_utc_and_offset(utc, offset)
+                        .format(pattern)
+                        .to_string(),
+                    None => r.timestamp.format(pattern).to_string(),
+                }
+            }
+            _ => r.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        }
+    }
+
+    fn price(&self, p: Option<f64>) -> String {
+        match (p, self.decimals) {
+            (Some(p), Some(decimals)) => format!("{:.*}", decimals, p),
+            (Some(p), None) => float(p),
+            (None, _) => String::new(),
+        }
+    }
+
+    fn cell(&self, r: &Record, column: &str) -> String {
+        let number = |v: Option<f64>| v.map_or(String::new(), float);
+        match column {
+            "timestamp" => self.timestamp(r),
+            "volume" => r.volume.map_or(String::new(), |v| v.to_string()),
+            "high" => self.price(r.high),
+            "low" => self.price(r.low),
+            "open" => self.price(r.open),
+            "close" => self.price(r.close),
+            "adjclose" => self.price(r.adjclose),
+            "split" => r.split.clone().unwrap_or_default(),
+            "split_factor" => number(r.split_factor),
+            "dividend" => number(r.dividend),
+            "capital_gain" => number(r.capital_gain),
+            _ => float(r.cum_split_factor),
+        }
+    }
+}
+
+/// A csv writer in the dialect of [CsvArgs]. With `tagged` every record is prefixed with the
+/// [crate::v8chart::Tags] of its dataset so that many datasets can share one output
+pub struct CsvSink<W: Write> {
+    wtr: Writer<W>,
+    args: CsvArgs,
+    tagged: bool,
+    /// whether the header is still to be written
+    header: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(writer: W, args: CsvArgs, tagged: bool) -> CsvSink<W> {
+        let wtr = WriterBuilder::new()
+            .delimiter(args.delimiter.0)
+            .quote_style(args.quote.into())
+            .from_writer(writer);
+        CsvSink {
+            wtr,
+            header: !args.no_header,
+            args,
+            tagged,
+        }
+    }
+
+    fn columns(&self) -> &[&'static str] {
+        self.args.columns.as_ref().map_or(&COLUMNS[..], |c| &c.0[..])
+    }
+
+    /// append the records of a dataset
+    pub fn write(&mut self, ds: &DataSet) -> Result<(), Box<dyn Error>> {
+        let columns = self.columns().to_vec();
+        if self.header {
+            let tags = if self.tagged { &TAGS[..] } else { &[] };
+            self.wtr.write_record(tags.iter().chain(columns.iter()))?;
+            self.header = false;
+        }
+        let t = ds.tags();
+        let tags = if self.tagged {
+            vec![t.symbol, t.exchange, t.currency, t.interval]
+        } else {
+            Vec::new()
+        };
+        let cells = Cells::new(&self.args, ds);
+        for r in ds.records.iter() {
+            let row = columns.iter().map(|c| cells.cell(r, c));
+            self.wtr.write_record(tags.iter().map(|t| t.to_string()).chain(row))?;
+        }
+        self.wtr.flush()?;
+        Ok(())
+    }
+
+    pub fn close(mut self) -> Result<(), Box<dyn Error>> {
+        Ok(self.wtr.flush()?)
+    }
+}
+
+/// Parses the cells of a csv in a dialect back into the fields of a [Record]
+struct Parser<'a> {
+    args: &'a CsvArgs,
+    local: Local,
+    offset: FixedOffset,
+}
+
+impl<'a> Parser<'a> {
+    fn new(args: &'a CsvArgs, ds: &DataSet) -> Parser<'a> {
+        Parser {
+            args,
+            local: Local::new(ds),
+            offset: ds.meta.offset(),
+        }
+    }
+
+    /// a local time of the exchange as a timestamp in its offset
+    fn local_time(&self, local: &NaiveDateTime) -> DateTime<FixedOffset> {
+        DateTime::from_naive_utc_and_offset(self.local.to_utc(local), self.offset)
+    }
+
+    /// Dates are read as the exchange-local midnight. As intraday bars fall back to RFC 3339 when
+    /// written as dates, that is tried first
+    fn timestamp(&self, cell: &str) -> Result<DateTime<FixedOffset>, String> {
+        let epoch = |t: Option<DateTime<chrono::Utc>>| {
+            t.map(|t| t.with_timezone(&self.offset))
+                .ok_or_else(|| format!("timestamp {} out of range", cell))
+        };
+        let rfc3339 = || DateTime::parse_from_rfc3339(cell).map(|t| t.with_timezone(&self.offset));
+        let date = |pattern: &str| {
+            NaiveDate::parse_from_str(cell, pattern).map(|d| self.local_time(&d.and_hms_opt(0, 0, 0).unwrap()))
+        };
+        let invalid = |err: chrono::ParseError| format!("invalid timestamp {}: {}", cell, err);
+        match &self.args.timestamp_format {
+            TimestampFormat::Rfc3339 => rfc3339().map_err(invalid),
+            TimestampFormat::Epoch => epoch(DateTime::from_timestamp(parse(cell)?, 0)),
+            TimestampFormat::EpochMs => epoch(DateTime::from_timestamp_millis(parse(cell)?)),
+            TimestampFormat::Date => rfc3339().or_else(|_| date("%Y-%m-%d")).map_err(invalid),
+            TimestampFormat::Strftime(pattern) => DateTime::parse_from_str(cell, pattern)
+                .map(|t| t.with_timezone(&self.offset))
+                .or_else(|_| NaiveDateTime::parse_from_str(cell, pattern).map(|t| self.local_time(&t)))
+                .or_else(|_| date(pattern))
+                .map_err(invalid),
+        }
+    }
+
+    /// parse a cell into a column of `r`, empty cells are left as they are
+    fn cell(&self, r: &mut Record, column: &str, cell: &str) -> Result<(), String> {
+        if cell.is_empty() {
+            return Ok(());
+        }
+        match column {
+            "timestamp" => r.timestamp = self.timestamp(cell)?,
+            "volume" => r.volume = Some(parse(cell)?),
+            "high" => r.high = Some(parse(cell)?),
+            "low" => r.low = Some(parse(cell)?),
+            "open" => r.open = Some(parse(cell)?),
+            "close" => r.close = Some(parse(cell)?),
+            "adjclose" => r.adjclose = Some(parse(cell)?),
+            "split" => r.split = Some(cell.to_string()),
+            "split_factor" => r.split_factor = Some(parse(cell)?),
+            "dividend" => r.dividend = Some(parse(cell)?),
+            "capital_gain" => r.capital_gain = Some(parse(cell)?),
+            _ => r.cum_split_factor = parse(cell)?,
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(cell: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    cell.parse().map_err(|err| format!("invalid number {}: {}", cell, err))
+}
+
+/// Read a csv in the dialect of [CsvArgs] back into the records of `ds`, the counterpart of
+/// [CsvSink]. Columns are matched by the header, or taken from `columns` when there is none.
+/// Unknown columns such as the tags of a combined csv are ignored and missing ones are left empty
+pub fn read_records<R: Read>(reader: R, args: &CsvArgs, ds: &DataSet) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut rdr = ReaderBuilder::new()
+        .delimiter(args.delimiter.0)
+        .has_headers(!args.no_header)
+        .from_reader(reader);
+    let columns: Vec<Option<&str>> = if args.no_header {
+        let columns = args.columns.as_ref().map_or(&COLUMNS[..], |c| &c.0[..]);
+        columns.iter().map(|c| Some(*c)).collect()
+    } else {
+        let header = rdr.headers()?;
+        header
+            .iter()
+            .map(|h| COLUMNS.iter().find(|c| **c == h).copied())
+            .collect()
+    };
+    if !columns.contains(&Some("timestamp")) {
+        return Err("there is no timestamp column".into());
+    }
+    let parser = Parser::new(args, ds);
+    let mut records = Vec::new();
+    for row in rdr.records() {
+        let row = row?;
+        let mut r = Record {
+            timestamp: DateTime::default(),
+            volume: None,
+            high: None,
+            low: None,
+            open: None,
+            close: None,
+            adjclose: None,
+            split: None,
+            split_factor: None,
+            dividend: None,
+            capital_gain: None,
+            cum_split_factor: 1.0,
+        };
+        for (column, cell) in columns.iter().zip(row.iter()) {
+            if let Some(column) = column {
+                parser
+                    .cell(&mut r, column, cell.trim())
+                    .map_err(|err| format!("{} on line {}", err, row.position().map_or(0, |p| p.line())))?;
+            }
+        }
+        records.push(r);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    fn write(ds: &DataSet, args: &CsvArgs) -> Vec<String> {
+        let mut buf = Vec::new();
+        let mut sink = CsvSink::new(&mut buf, args.clone(), false);
+        sink.write(ds).unwrap();
+        sink.close().unwrap();
+        String::from_utf8(buf).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("tab".parse::<Delimiter>().unwrap(), Delimiter(b'\t'));
+        assert_eq!(";".parse::<Delimiter>().unwrap(), Delimiter(b';'));
+        assert!(";;".parse::<Delimiter>().is_err());
+        assert_eq!(
+            "close, timestamp".parse::<Columns>().unwrap(),
+            Columns(vec!["close", "timestamp"])
+        );
+        assert!("close,price".parse::<Columns>().is_err());
+        assert_eq!(
+            "%Y-%m-%d %H:%M".parse::<TimestampFormat>().unwrap(),
+            TimestampFormat::Strftime("%Y-%m-%d %H:%M".to_string())
+        );
+        assert!("%Q".parse::<TimestampFormat>().is_err());
+        assert!("iso".parse::<TimestampFormat>().is_err());
+    }
+
+    #[test]
+    fn test_default_dialect() {
+        // the same output as serializing the records
+        let ds = load("assets/AAPL_init_20210126.json");
+        let mut wtr = Writer::from_writer(Vec::new());
+        for r in ds.records.iter() {
+            wtr.serialize(r).unwrap();
+        }
+        let expected = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        let lines = write(&ds, &CsvArgs::default());
+        assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_dialect() {
+        let ds = load("assets/^GSPC_20210104_20210108.json");
+        let args = CsvArgs {
+            delimiter: Delimiter(b';'),
+            quote: Quoting::NonNumeric,
+            no_header: true,
+            columns: Some("timestamp,close,volume".parse().unwrap()),
+            timestamp_format: TimestampFormat::Date,
+            round: true,
+        };
+        let lines = write(&ds, &args);
+        assert_eq!(lines.len(), ds.records.len());
+        assert_eq!(lines[0], "\"2021-01-04\";3700.65;5006680000");
+
+        let args = CsvArgs {
+            columns: Some("timestamp,open".parse().unwrap()),
+            timestamp_format: TimestampFormat::EpochMs,
+            ..CsvArgs::default()
+        };
+        assert_eq!(
+            write(&ds, &args)[..2],
+            ["timestamp,open", "1609770600000,3764.610107421875"]
+        );
+
+        let args = CsvArgs {
+            columns: Some("timestamp".parse().unwrap()),
+            timestamp_format: "%d/%m/%Y %H:%M".parse().unwrap(),
+            ..CsvArgs::default()
+        };
+        assert_eq!(write(&ds, &args)[1], "04/01/2021 09:30");
+
+        // intraday bars keep their time
+        let ds = load("assets/AAPL_5m_20210125.json");
+        let args = CsvArgs {
+            columns: Some("timestamp".parse().unwrap()),
+            timestamp_format: TimestampFormat::Date,
+            ..CsvArgs::default()
+        };
+        assert_eq!(write(&ds, &args)[1], "2021-01-25T09:30:00-05:00");
+    }
+
+    #[test]
+    fn test_read_records() {
+        let as_json = |records: &[Record]| serde_json::to_string(records).unwrap();
+        let ds = load("assets/AAPL_init_20210126.json");
+        let args = CsvArgs::default();
+        let read = |args: &CsvArgs, ds: &DataSet| {
+            let mut buf = Vec::new();
+            let mut sink = CsvSink::new(&mut buf, args.clone(), false);
+            sink.write(ds).unwrap();
+            sink.close().unwrap();
+            read_records(&buf[..], args, ds).unwrap()
+        };
+        assert_eq!(as_json(&read(&args, &ds)), as_json(&ds.records));
+
+        let args = CsvArgs {
+            delimiter: Delimiter(b'\t'),
+            quote: Quoting::Always,
+            no_header: true,
+            columns: Some("close,timestamp,split".parse().unwrap()),
+            timestamp_format: TimestampFormat::EpochMs,
+            ..CsvArgs::default()
+        };
+        let records = read(&args, &ds);
+        assert_eq!(records.len(), ds.records.len());
+        for (r, expected) in records.iter().zip(ds.records.iter()) {
+            assert_eq!(r.timestamp, expected.timestamp);
+            assert_eq!(r.close, expected.close);
+            assert_eq!(r.split, expected.split);
+            assert_eq!(r.open, None);
+        }
+
+        // dates are the exchange-local midnight
+        let ds = load("assets/^GSPC_20210104_20210108.json");
+        let args = CsvArgs {
+            timestamp_format: TimestampFormat::Date,
+            ..CsvArgs::default()
+        };
+        let records = read(&args, &ds);
+        assert_eq!(records[0].timestamp.to_rfc3339(), "2021-01-04T00:00:00-05:00");
+        assert_eq!(records[3].close, ds.records[3].close);
+
+        // summer bars are written and read in daylight time though the meta is in standard time
+        let ds = load("assets/AAPL_30m_20200706_20200707.json");
+        let args = CsvArgs {
+            columns: Some("timestamp,close".parse().unwrap()),
+            timestamp_format: "%Y-%m-%d %H:%M".parse().unwrap(),
+            ..CsvArgs::default()
+        };
+        assert_eq!(write(&ds, &args)[1], "2020-07-06 09:30,92.82");
+        let records = read(&args, &ds);
+        assert_eq!(records.len(), ds.records.len());
+        for (r, expected) in records.iter().zip(ds.records.iter()) {
+            assert_eq!(r.timestamp, expected.timestamp);
+            assert_eq!(r.close, expected.close);
+        }
+
+        // the tags of a combined csv are skipped
+        let csv = "symbol,timestamp,close\nAAPL,2021-01-25T09:30:00-05:00,142.92\n";
+        let records = read_records(csv.as_bytes(), &CsvArgs::default(), &ds).unwrap();
+        assert_eq!(records[0].close, Some(142.92));
+        let csv = "timestamp,close\n2021-01-25,142.92\n";
+        assert!(read_records(csv.as_bytes(), &CsvArgs::default(), &ds).is_err());
+        assert!(read_records("close\n1.0\n".as_bytes(), &CsvArgs::default(), &ds).is_err());
+    }
+}
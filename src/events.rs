@@ -0,0 +1,135 @@
+use crate::resample::Local;
+use crate::v8chart::{DataSet, EventRecord};
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use csv::Writer;
+use serde::Serialize;
+use std::{error::Error, fs::File, io::BufWriter, io::Write, path::Path};
+
+/// The kinds of events and the suffix of the file they are exported to
+pub const EXPORTS: [(&str, &str); 3] = [
+    ("dividend", "_dividends.csv"),
+    ("split", "_splits.csv"),
+    ("capital_gain", "_capital_gains.csv"),
+];
+
+/// a dividend or capital gain
+#[derive(Serialize)]
+struct Cash<'a> {
+    /// the exchange-local date, i.e. the ex-date of a dividend
+    date: NaiveDate,
+    timestamp: &'a DateTime<FixedOffset>,
+    amount: f64,
+}
+
+#[derive(Serialize)]
+struct Split<'a> {
+    date: NaiveDate,
+    timestamp: &'a DateTime<FixedOffset>,
+    ratio: &'a str,
+    factor: f64,
+}
+
+/// the events of a dataset of one kind
+pub fn events_of<'a>(ds: &'a DataSet, kind: &'a str) -> impl Iterator<Item = &'a EventRecord> {
+    ds.events.iter().filter(move |e| e.kind == kind)
+}
+
+/// Write every event of a kind as csv with its exchange-local date, including the ones that do
+/// not line up with a bar. Returns the number of events
+pub fn write_events<W: Write>(ds: &DataSet, kind: &str, writer: W) -> Result<usize, Box<dyn Error>> {
+    let local = Local::new(ds);
+    let mut wtr = Writer::from_writer(writer);
+    let mut count = 0;
+    for e in events_of(ds, kind) {
+        let date = local.to_local(&e.timestamp.naive_utc()).date();
+        match e.ratio.as_deref() {
+            Some(ratio) => wtr.serialize(Split {
+                date,
+                timestamp: &e.timestamp,
+                ratio,
+                factor: e.amount,
+            })?,
+            None => wtr.serialize(Cash {
+                date,
+                timestamp: &e.timestamp,
+                amount: e.amount,
+            })?,
+        }
+        count += 1;
+    }
+    wtr.flush()?;
+    Ok(count)
+}
+
+/// write every event of a kind to path, see [write_events]
+pub fn write_events_to<P: AsRef<Path>>(ds: &DataSet, kind: &str, path: P) -> Result<usize, Box<dyn Error>> {
+    write_events(ds, kind, BufWriter::new(File::create(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    fn write(ds: &DataSet, kind: &str) -> Vec<String> {
+        let mut buf = Vec::new();
+        write_events(ds, kind, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_attach_to_period() {
+        // a friday dividend and a split stamped at the open while weekly bars start on monday
+        // midnight
+        let ds = load("assets/AAPL_1wk_20200803_20200904.json");
+        assert_eq!(ds.events.len(), 2);
+        let dividends: Vec<Option<f64>> = ds.records.iter().map(|r| r.dividend).collect();
+        assert_eq!(dividends, vec![Some(0.205), None, None, None, None]);
+        assert_eq!(ds.records[4].split.as_deref(), Some("4:1"));
+        assert_eq!(ds.records[4].split_factor, Some(4.0));
+        assert_eq!(ds.records[0].cum_split_factor, 4.0);
+        assert_eq!(ds.records[4].cum_split_factor, 1.0);
+
+        // events before the first bar are not attached but still exported
+        let mut ds = load("assets/AAPL_1wk_20200803_20200904.json");
+        ds.records.remove(0);
+        ds.attach_events();
+        assert!(ds.records.iter().all(|r| r.dividend.is_none()));
+        assert_eq!(write(&ds, "dividend").len(), 2);
+    }
+
+    #[test]
+    fn test_write_events() {
+        let ds = load("assets/AAPL_1wk_20200803_20200904.json");
+        assert_eq!(
+            write(&ds, "dividend"),
+            vec!["date,timestamp,amount", "2020-08-07,2020-08-07T08:30:00-05:00,0.205"]
+        );
+        assert_eq!(
+            write(&ds, "split"),
+            vec![
+                "date,timestamp,ratio,factor",
+                "2020-08-31,2020-08-31T08:30:00-05:00,4:1,4.0"
+            ]
+        );
+        assert!(write(&ds, "capital_gain").is_empty());
+
+        let ds = load("assets/AAPL_init_20210126.json");
+        let splits = write(&ds, "split");
+        assert_eq!(splits.len(), 6);
+        assert_eq!(
+            ds.records.iter().filter(|r| r.split.is_some()).count(),
+            splits.len() - 1
+        );
+        assert_eq!(
+            ds.records.iter().filter(|r| r.dividend.is_some()).count(),
+            write(&ds, "dividend").len() - 1
+        );
+    }
+}
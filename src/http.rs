@@ -1,4 +1,5 @@
 use crate::options::DownloadOpts as Opts;
+use crate::v8chart::{ChartWrapper, DataSet};
 
 use chrono::Local;
 use std::path::{Path, PathBuf};
@@ -9,9 +10,13 @@ use tokio::{io, time::sleep};
 use futures::stream::{StreamExt, TryStreamExt};
 use hyper::{
     body::{to_bytes, Bytes},
-    Body, Response, StatusCode,
+    client::HttpConnector,
+    Body, Client, Request, Response, StatusCode,
 };
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+use hyper_tls::HttpsConnector;
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+/// A hyper client that speaks both http and https
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
 
 /// A custom error that captures http status and response
 #[derive(Debug)]
@@ -23,12 +28,68 @@ struct DownloadError {
 
 impl std::fmt::Display for DownloadError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        write!(
+            f,
+            "failed to download {} with status {}: {:?}",
+            self.symbol, self.status, self.body
+        )
     }
 }
 
 impl std::error::Error for DownloadError {}
 
+/// build a client that can talk to yahoo over https
+pub fn make_client() -> HttpsClient {
+    let https = HttpsConnector::new();
+    Client::builder().build::<_, Body>(https)
+}
+
+/// GET a uri and buffer the whole body, a non-2xx status is turned into a [DownloadError]
+pub async fn get_bytes(client: &HttpsClient, uri: hyper::Uri, symbol: &str) -> Result<Bytes> {
+    let resp = client.get(uri).await?;
+    read_body(resp, symbol).await
+}
+
+/// POST a JSON body to a uri and buffer the whole response body
+pub async fn post_json(client: &HttpsClient, uri: hyper::Uri, json: String, symbol: &str) -> Result<Bytes> {
+    post(client, uri, "application/json", None, json, symbol).await
+}
+
+/// POST a body of `content_type` to a uri with an optional authorization header and buffer the
+/// whole response body
+pub async fn post(
+    client: &HttpsClient,
+    uri: hyper::Uri,
+    content_type: &str,
+    authorization: Option<&str>,
+    body: String,
+    symbol: &str,
+) -> Result<Bytes> {
+    let mut req = Request::post(uri).header("content-type", content_type);
+    if let Some(authorization) = authorization {
+        req = req.header("authorization", authorization);
+    }
+    let resp = client.request(req.body(Body::from(body))?).await?;
+    read_body(resp, symbol).await
+}
+
+async fn read_body(mut resp: Response<Body>, symbol: &str) -> Result<Bytes> {
+    debug!(
+        "content type: {:?}, status: {:}",
+        resp.headers().get("content-type"),
+        resp.status()
+    );
+    match resp.status() {
+        status if status.is_success() => Ok(to_bytes(resp.body_mut()).await?),
+        status => Err(DownloadError {
+            status,
+            symbol: symbol.to_owned(),
+            body: to_bytes(resp.body_mut()).await?,
+        }
+        .into()),
+    }
+}
+
 /// Entry function that takes a [crate::options::DownloadOpts] to download from Yahoo Finance
 /// V8 chart API and write the JSONs into files
 pub async fn download(opts: &Opts) -> Vec<(PathBuf, Result<()>)> {
@@ -41,8 +102,7 @@ pub async fn download(opts: &Opts) -> Vec<(PathBuf, Result<()>)> {
         }
     }
 
-    let https = hyper_tls::HttpsConnector::new();
-    let client_arc = Arc::new(hyper::Client::builder().build::<_, hyper::Body>(https));
+    let client_arc = Arc::new(make_client());
 
     let mut tasks = Vec::new();
     let mut paths = Vec::new();
@@ -56,10 +116,9 @@ pub async fn download(opts: &Opts) -> Vec<(PathBuf, Result<()>)> {
                 .map_or("init".to_string(), |s| s.format("%Y%m%d").to_string()),
             opts.end
                 .unwrap_or_else(|| Local::now().naive_local().date())
-                .format("%Y%m%d")
-                .to_string(),
+                .format("%Y%m%d"),
         );
-        let uri = make_uri(&opts, symb);
+        let uri = make_uri(opts, symb);
         let pathbuf = out_dir.join(filename);
         paths.push(pathbuf.clone());
         sleep(opts.rate.0).await;
@@ -114,7 +173,7 @@ Use Tokio to pipe reader to writer as stream
 async fn write_to_file(mut resp: Response<Body>, path: &Path) -> Result<()> {
     let futures_io_async_read = resp
         .body_mut()
-        .map(|result| result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string())))
+        .map(|result| result.map_err(|err| std::io::Error::other(err.to_string())))
         .into_async_read();
     let mut tokio_async_read = tokio_util::compat::FuturesAsyncReadCompatExt::compat(futures_io_async_read);
 
@@ -126,20 +185,26 @@ async fn write_to_file(mut resp: Response<Body>, path: &Path) -> Result<()> {
 
 /// compose a V8 API request URI
 fn make_uri(opts: &Opts, symbol: &str) -> hyper::Uri {
-    let base = format!("https://query1.finance.yahoo.com/v8/finance/chart/{}", symbol);
     let start = opts
         .start
-        .map_or("0".to_string(), |s| s.and_hms(0, 0, 0).timestamp().to_string());
-    let end = opts.end.map_or("99999999999".to_string(), |s| {
-        s.and_hms(0, 0, 0).timestamp().to_string()
-    });
+        .map_or(0, |s| s.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp());
+    let end = opts
+        .end
+        .map_or(99999999999, |s| s.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp());
+    chart_uri(symbol, start, end, &opts.interval, opts.include_pre_post)
+}
+
+/// compose a V8 API request URI for a period in epoch seconds
+fn chart_uri(symbol: &str, start: i64, end: i64, interval: &str, include_pre_post: bool) -> hyper::Uri {
+    let base = format!("https://query1.finance.yahoo.com/v8/finance/chart/{}", symbol);
     let url = url::Url::parse_with_params(
         base.as_str(),
         &[
-            ("period1", start),
-            ("period2", end),
-            ("interval", opts.interval.to_owned()),
-            ("events", "div,split".to_string()),
+            ("period1", start.to_string()),
+            ("period2", end.to_string()),
+            ("interval", interval.to_owned()),
+            ("events", "div,split,capitalGain".to_string()),
+            ("includePrePost", include_pre_post.to_string()),
         ],
     )
     .unwrap();
@@ -147,6 +212,30 @@ fn make_uri(opts: &Opts, symbol: &str) -> hyper::Uri {
     url.into_string().parse().unwrap()
 }
 
+/// download a period of a symbol straight into datasets without touching the disk
+pub async fn fetch_chart(
+    client: &HttpsClient,
+    symbol: &str,
+    start: i64,
+    end: i64,
+    interval: &str,
+) -> Result<Vec<DataSet>> {
+    let body = get_bytes(client, chart_uri(symbol, start, end, interval, false), symbol).await?;
+    let chart_wrapper: ChartWrapper = serde_json::from_slice(&body)?;
+    Ok(chart_wrapper.chart.into())
+}
+
+/// Download a symbol as [crate::options::DownloadOpts] asks and parse it in memory instead of
+/// writing the JSON into a file
+pub async fn fetch(client: &HttpsClient, opts: &Opts, symbol: &str) -> Result<Vec<DataSet>> {
+    let body = get_bytes(client, make_uri(opts, symbol), symbol).await?;
+    let chart_wrapper: ChartWrapper = serde_json::from_slice(&body)?;
+    if let Some(err) = chart_wrapper.chart.error.as_ref() {
+        error!("{} contains an error {}: {}", symbol, err.code, err.description);
+    }
+    Ok(chart_wrapper.chart.into())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -170,13 +259,15 @@ mod tests {
             .collect();
         Opts {
             symbols: vec!["GXY.AX".to_string(), "A2M.AX".to_string()],
-            start: Some(NaiveDate::from_ymd(2020, 1, 3)),
-            end: Some(NaiveDate::from_ymd(2020, 1, 7)),
+            start: Some(NaiveDate::from_ymd_opt(2020, 1, 3).unwrap()),
+            end: Some(NaiveDate::from_ymd_opt(2020, 1, 7).unwrap()),
             include_pre_post: true,
             output_dir: std::env::temp_dir().join(prefix).to_str().unwrap().to_string(),
             interval: "1d".to_string(),
             rate: "500".parse().unwrap(),
             convert: false,
+            convert_args: Default::default(),
+            store: None,
         }
     }
 
@@ -201,7 +292,7 @@ mod tests {
     async fn test_download_fail() {
         init();
         let mut opts = make_opts();
-        opts.start = Some(NaiveDate::from_ymd(2020, 1, 10));
+        opts.start = Some(NaiveDate::from_ymd_opt(2020, 1, 10).unwrap());
         let path_results = download(&opts).await;
         assert_remove(path_results, 0, &opts.output_dir);
     }
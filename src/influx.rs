@@ -0,0 +1,216 @@
+use crate::http::{post, HttpsClient, Result};
+use crate::v8chart::{DataSet, Record, Tags};
+
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// The measurement every bar is written to
+pub const MEASUREMENT: &str = "ohlcv";
+/// Lines per request when pushing to a write endpoint
+pub const BATCH_SIZE: usize = 5000;
+
+/// escape commas, equal signs and spaces in a tag key or value
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The tag set shared by every line of a dataset, an empty value is left out as line protocol
+/// does not allow it
+fn tag_set(tags: &Tags) -> String {
+    let mut set = String::from(MEASUREMENT);
+    for (key, value) in [
+        ("symbol", tags.symbol),
+        ("exchange", tags.exchange),
+        ("currency", tags.currency),
+        ("interval", tags.interval),
+    ]
+    .iter()
+    {
+        if !value.is_empty() {
+            let _ = write!(set, ",{}={}", key, escape(value));
+        }
+    }
+    set
+}
+
+/// A record as a line of `tag_set`, its non-null fields and its timestamp in nanoseconds. None
+/// when every field is null as a line needs at least one field
+fn line(tag_set: &str, r: &Record) -> Option<String> {
+    let mut fields = Vec::new();
+    for (key, value) in [
+        ("open", r.open),
+        ("high", r.high),
+        ("low", r.low),
+        ("close", r.close),
+        ("adjclose", r.adjclose),
+    ]
+    .iter()
+    {
+        if let Some(value) = value {
+            fields.push(format!("{}={}", key, value));
+        }
+    }
+    if let Some(volume) = r.volume {
+        fields.push(format!("volume={}i", volume));
+    }
+    if fields.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} {} {}",
+        tag_set,
+        fields.join(","),
+        r.timestamp.timestamp() * 1_000_000_000
+    ))
+}
+
+impl DataSet {
+    /// the records as InfluxDB line protocol in measurement [MEASUREMENT]
+    pub fn to_lines(&self) -> Vec<String> {
+        let tag_set = tag_set(&self.tags());
+        self.records.iter().filter_map(|r| line(&tag_set, r)).collect()
+    }
+}
+
+/// Append a dataset as line protocol
+pub fn write_lines<W: Write>(ds: &DataSet, writer: &mut W) -> std::result::Result<(), Box<dyn Error>> {
+    for line in ds.to_lines() {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// write a dataset to path as line protocol
+pub fn write_to_influx<P: AsRef<Path>>(ds: &DataSet, path: P) -> std::result::Result<(), Box<dyn Error>> {
+    write_lines(ds, &mut BufWriter::new(File::create(path)?))
+}
+
+/// Post a dataset to a write endpoint such as `http://localhost:8086/api/v2/write?bucket=b&precision=ns`
+/// in batches of [BATCH_SIZE] lines. `token` goes into the authorization header when given.
+/// Returns the number of lines written
+pub async fn push(client: &HttpsClient, uri: &hyper::Uri, token: Option<&str>, ds: &DataSet) -> Result<usize> {
+    let lines = ds.to_lines();
+    let authorization = token.map(|token| format!("Token {}", token));
+    for batch in lines.chunks(BATCH_SIZE) {
+        let mut body = batch.join("\n");
+        body.push('\n');
+        post(
+            client,
+            uri.clone(),
+            "text/plain; charset=utf-8",
+            authorization.as_deref(),
+            body,
+            &ds.meta.symbol,
+        )
+        .await?;
+    }
+    Ok(lines.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::make_client;
+    use crate::v8chart::load_from_json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_lines() {
+        let ds = load("assets/^GSPC_20210104_20210108.json");
+        let lines = ds.to_lines();
+        assert_eq!(lines.len(), ds.records.len());
+        assert_eq!(
+            lines[0],
+            "ohlcv,symbol=^GSPC,exchange=SNP,currency=USD,interval=1d open=3764.610107421875,\
+             high=3769.989990234375,low=3662.7099609375,close=3700.64990234375,adjclose=3700.64990234375,\
+             volume=5006680000i 1609770600000000000"
+        );
+        assert_eq!(escape("ES=F a,b"), "ES\\=F\\ a\\,b");
+
+        let mut ds = load("assets/ES=F_20210104_20210106.json");
+        ds.meta.currency = None;
+        let before = ds.to_lines().len();
+        let r = &mut ds.records[0];
+        r.open = None;
+        r.high = None;
+        r.low = None;
+        r.close = None;
+        r.adjclose = None;
+        r.volume = None;
+        let lines = ds.to_lines();
+        assert_eq!(lines.len(), before - 1);
+        assert!(lines[0].starts_with("ohlcv,symbol=ES\\=F,exchange=CME,interval=1d "));
+    }
+
+    /// a stand-in write endpoint that answers 204 to every request and hands back the bodies
+    async fn serve(listener: TcpListener, requests: usize) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for _ in 0..requests {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 4096];
+            let body = loop {
+                let n = socket.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&buf).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length: usize = text[..end]
+                        .lines()
+                        .find_map(|l| {
+                            l.to_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().to_string())
+                        })
+                        .map_or(0, |v| v.parse().unwrap());
+                    if buf.len() >= end + 4 + length {
+                        assert!(text.starts_with("POST /write?db=bars "));
+                        assert!(text.to_lowercase().contains("authorization: token secret"));
+                        break text[end + 4..].to_string();
+                    }
+                }
+            };
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            bodies.push(body);
+        }
+        bodies
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri: hyper::Uri = format!("http://{}/write?db=bars", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server = tokio::spawn(serve(listener, 1));
+        let ds = load("assets/^GSPC_20210104_20210108.json");
+        let written = push(&make_client(), &uri, Some("secret"), &ds).await.unwrap();
+        assert_eq!(written, ds.records.len());
+        let bodies = server.await.unwrap();
+        assert_eq!(bodies[0].lines().count(), ds.records.len());
+        assert_eq!(bodies[0].lines().next().unwrap(), ds.to_lines()[0]);
+    }
+}
@@ -1,15 +1,45 @@
 use std::{
-    fs::read_dir,
+    fs::{read_dir, File},
+    io::{stdout, BufWriter, Write},
     iter::{empty, once},
     path::{Path, PathBuf},
 };
 
-use options::SubCommand;
-use v8chart::{load_from_json, write_to_csv, DataSet};
+use adjust::Adjustment;
+use columnar::{FeatherSink, ParquetSink};
+use dialect::CsvSink;
+use options::{
+    ConvertArgs, ConvertOpts, DownloadOpts, Format, InputFormat, LoadPostgresOpts, SubCommand, ValidateOpts,
+    VerifyAdjustedOpts,
+};
+use panel::Panel;
+use resample::Interval;
+use sidecar::{load_sidecar, sidecar_path, write_sidecar, Request, Sidecar};
+use store::{Store, StoreUrl};
+use v8chart::{load_chart, load_from_json, write_tagged_jsonl, write_to_csv, write_to_jsonl, DataSet};
+use validate::Severity;
+use xlsx::XlsxSink;
 
+mod adjust;
+mod columnar;
+mod dialect;
+mod events;
 mod http;
+mod influx;
+mod merge;
+mod news;
 mod options;
+mod panel;
+mod postgres;
+mod repair;
+mod resample;
+mod screener;
+mod sidecar;
+mod store;
+mod stream;
 mod v8chart;
+mod validate;
+mod xlsx;
 #[macro_use]
 extern crate log;
 
@@ -32,70 +62,621 @@ fn walk_dir<P: AsRef<Path>>(dir: P, recursive: bool) -> Box<dyn Iterator<Item =
     }
 }
 
-/// convert a json at a path to CSVs in the same path
-fn convert(path: PathBuf) {
-    match load_from_json(path.as_path().to_str().unwrap()) {
-        Ok(chart_wrapper) => {
-            let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+/// a yahoo json, not a `.meta.json` sidecar
+fn is_chart_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json") && !path.to_string_lossy().ends_with(".meta.json")
+}
+
+/// whether a file under an input dir is read as `from`. csv and json lines are only read when
+/// they have a sidecar, the others are skipped with a warning unless they are repair logs or
+/// event exports
+fn is_input(path: &Path, from: InputFormat) -> bool {
+    let extension = match from {
+        InputFormat::Json => return is_chart_json(path),
+        InputFormat::Csv => "csv",
+        InputFormat::Jsonl => "jsonl",
+    };
+    if path.extension().is_none_or(|ext| ext != extension) {
+        return false;
+    }
+    if sidecar_path(path).exists() {
+        return true;
+    }
+    let name = path.to_string_lossy();
+    let log = name.ends_with(".repairs.csv") || events::EXPORTS.iter().any(|(_, suffix)| name.ends_with(suffix));
+    if !log {
+        warn!("skipping {:?} as there is no sidecar {:?}", path, sidecar_path(path));
+    }
+    false
+}
+
+/// paths next to the json at `path` for each of its `count` datasets, `{stem}{suffix}` when there
+/// is only one and `{stem}_{i}{suffix}` otherwise
+fn sibling_paths(path: &Path, count: usize, suffix: &str) -> Vec<PathBuf> {
+    let stem = path
+        .file_stem()
+        .map_or("unknown_stem", |s| s.to_str().unwrap())
+        .to_string();
+    if count == 1 {
+        vec![path.with_file_name(format!("{}{}", stem, suffix))]
+    } else {
+        (0..count)
+            .map(|i| path.with_file_name(format!("{}_{}{}", stem, i, suffix)))
+            .collect()
+    }
+}
+
+/// load the datasets of a json, or of a csv or json lines output, then repair and adjust them as
+/// `args` asks, repairs are logged to `.repairs.csv` next to the input
+async fn load_datasets(path: &Path, args: &ConvertArgs) -> Option<Vec<DataSet>> {
+    let chart_wrapper = match load_chart(path, &args.csv) {
+        Ok(chart_wrapper) => chart_wrapper,
+        Err(err) => {
+            error!("failed to load {:?} with {:?}", path, err);
+            return None;
+        }
+    };
+    if args.adjust != Adjustment::None && !is_chart_json(path) {
+        if let Ok(sidecar) = load_sidecar(sidecar_path(path)) {
+            if sidecar.adjust != Adjustment::None.to_string() {
+                error!(
+                    "refusing to adjust {:?} again as it is adjusted for {}",
+                    path, sidecar.adjust
+                );
+                return None;
+            }
+        }
+    }
+    if let Some(err) = chart_wrapper.chart.error.as_ref() {
+        error!("{:?} contains an error {}: {}", path, err.code, err.description);
+    }
+    let mut ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+    let logs = sibling_paths(path, ds_vec.len(), ".repairs.csv");
+    prepare(&mut ds_vec, args, Some(&logs)).await;
+    Some(ds_vec)
+}
 
-            let outputs = if ds_vec.len() == 1 {
-                vec![path.with_extension("csv")]
-            } else {
-                let stem = path
-                    .file_stem()
-                    .map_or("unknown_stem", |s| s.to_str().unwrap())
-                    .to_string();
-                (0..ds_vec.len())
-                    .map(|i| path.with_file_name(format!("{:?}_{}.csv", stem, i)))
-                    .collect()
+/// repair and adjust datasets as `args` asks, repairs are logged to `logs` when given
+async fn prepare(ds_vec: &mut [DataSet], args: &ConvertArgs, logs: Option<&[PathBuf]>) {
+    if args.repair {
+        let client = http::make_client();
+        let now = chrono::Utc::now().timestamp();
+        for (i, ds) in ds_vec.iter_mut().enumerate() {
+            let symbol = ds.meta.symbol.clone();
+            let fetch = |start, end, interval| {
+                let fetched = http::fetch_chart(&client, &symbol, start, end, interval);
+                async {
+                    tokio::time::sleep(args.repair_rate.0).await;
+                    fetched.await
+                }
             };
+            let repairs = repair::repair(ds, now, fetch).await;
+            if repairs.is_empty() {
+                continue;
+            }
+            match logs.and_then(|logs| logs.get(i)) {
+                Some(log) => match repair::write_repairs(&repairs, log) {
+                    Ok(_) => info!("logged {} repairs to {:?}", repairs.len(), log),
+                    Err(err) => error!("failed to write repairs to {:?} with {:?}", log, err),
+                },
+                None => info!("made {} repairs to {}", repairs.len(), symbol),
+            }
+        }
+    }
+    ds_vec.iter_mut().for_each(|ds| ds.adjust(args.adjust));
+}
+
+/// convert a json at a path to CSVs in the same path, optionally resampled to a coarser interval
+async fn convert(path: PathBuf, args: &ConvertArgs, resample: Option<Interval>) {
+    if let Some(ds_vec) = load_datasets(&path, args).await {
+        write_converted(&path, ds_vec, args, resample);
+    }
+}
+
+/// write the datasets loaded from `path`, already repaired and adjusted, next to it in the format
+/// of `args`
+fn write_converted(path: &Path, mut ds_vec: Vec<DataSet>, args: &ConvertArgs, resample: Option<Interval>) {
+    if let Some(to) = resample {
+        ds_vec = match ds_vec.into_iter().map(|ds| ds.resample(to)).collect() {
+            Ok(ds_vec) => ds_vec,
+            Err(err) => {
+                error!("failed to resample {:?} with {}", path, err);
+                return;
+            }
+        };
+    }
+
+    // repaired or adjusted outputs of an archive are told apart from it by a suffix, an archive is
+    // never written over
+    let mut suffix = String::new();
+    if !is_chart_json(path) {
+        if args.repair {
+            suffix.push_str("_repaired");
+        }
+        if args.adjust != Adjustment::None {
+            suffix.push_str(&format!("_{}", args.adjust));
+        }
+    }
+    if let Some(to) = resample {
+        suffix.push_str(&format!("_{}", to));
+    }
+    let format = args.format;
+    let outputs = sibling_paths(path, ds_vec.len(), &format!("{}.{}", suffix, format.extension()));
+    if outputs.iter().any(|output| output == path) {
+        error!(
+            "refusing to write over the input {:?}, convert to another format instead",
+            path
+        );
+        return;
+    }
+    ds_vec.iter().zip(outputs.iter()).for_each(|(ds, path)| {
+        let result = match format {
+            Format::Csv => write_to_csv(ds, path, &args.csv),
+            Format::Jsonl => write_to_jsonl(ds, path),
+            Format::Influx => influx::write_to_influx(ds, path),
+            Format::Parquet => columnar::write_to_parquet(ds, path, args.compression),
+            Format::Arrow => columnar::write_to_feather(ds, path),
+            Format::Xlsx => xlsx::write_to_xlsx(ds, path),
+        };
+        if let Err(err) = result {
+            error!("failed to write to {} {:?} with {:?}", format.extension(), path, err);
+        } else {
+            info!("successfully converted to {:?}", path);
+        }
+    });
+    if args.events {
+        write_events(path, &ds_vec);
+    }
+    write_sidecars(path, &ds_vec, &outputs, args.adjust, None);
+}
+
+/// export the events of each dataset of the json at `path` to a csv per kind, only kinds that
+/// occur are written
+fn write_events(path: &Path, ds_vec: &[DataSet]) {
+    for (kind, suffix) in events::EXPORTS.iter() {
+        let outputs = sibling_paths(path, ds_vec.len(), suffix);
+        for (ds, output) in ds_vec.iter().zip(outputs.iter()) {
+            if events::events_of(ds, kind).next().is_none() {
+                continue;
+            }
+            match events::write_events_to(ds, kind, output) {
+                Ok(count) => info!("exported {} {} events to {:?}", count, kind, output),
+                Err(err) => error!("failed to export {} events to {:?} with {:?}", kind, output, err),
+            }
+        }
+    }
+}
 
-            ds_vec.iter().zip(outputs.iter()).for_each(|(ds, path)| {
-                if let Err(err) = write_to_csv(ds, path) {
-                    error!("failed to write to csv {:?} with {:?}", path, err);
-                } else {
-                    info!("successfully converted to {:?}", path);
+/// Write a sidecar next to each of the `outputs` of the datasets read from `path`, see
+/// [sidecar_path]. The request and fetch time are taken from `request` right after a download,
+/// and carried over from the sidecars of `path` otherwise
+fn write_sidecars(path: &Path, ds_vec: &[DataSet], outputs: &[PathBuf], adjust: Adjustment, request: Option<&Request>) {
+    let ext = path.extension().map_or("json", |e| e.to_str().unwrap());
+    let inputs = sibling_paths(path, ds_vec.len(), &format!(".{}", ext));
+    for ((ds, input), output) in ds_vec.iter().zip(inputs.iter()).zip(outputs.iter()) {
+        let (earlier, output) = (sidecar_path(input), sidecar_path(output));
+        let mut sidecar = Sidecar::new(ds, path);
+        sidecar.adjust = adjust.to_string();
+        match request {
+            Some(request) => {
+                sidecar.request = Some(request.clone());
+                sidecar.fetched_at = Some(chrono::Utc::now());
+            }
+            None => {
+                if let Ok(earlier) = load_sidecar(earlier) {
+                    sidecar.inherit(earlier);
                 }
-            });
+            }
+        }
+        if let Err(err) = write_sidecar(&sidecar, &output) {
+            error!("failed to write sidecar {:?} with {:?}", output, err);
         }
-        Err(err) => error!("failed to load json from {:?} with {:?}", path, err),
     }
 }
 
 /// wrapper over [`convert`] and [`walk_dir`]
-fn convert_to_csv(json_dir: &str, recursive: bool) -> std::io::Result<()> {
-    walk_dir(json_dir, recursive)
-        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
-        .for_each(convert);
+async fn convert_to_csv(
+    json_dir: &str,
+    recursive: bool,
+    args: &ConvertArgs,
+    resample: Option<Interval>,
+) -> std::io::Result<()> {
+    // collected first so that the outputs are not picked up as inputs of the same run
+    let paths: Vec<PathBuf> = walk_dir(json_dir, recursive)
+        .filter(|path| is_input(path, args.from))
+        .collect();
+    for path in paths {
+        convert(path, args, resample).await;
+    }
+    Ok(())
+}
+
+/// load every json in a directory and write them into one wide csv at `output`, `-` for stdout,
+/// see [panel::Panel]
+async fn convert_to_panel(opts: &ConvertOpts, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut datasets = Vec::new();
+    for path in walk_dir(&opts.input_dir, opts.recursive).filter(|path| is_input(path, opts.convert_args.from)) {
+        if let Some(mut ds_vec) = load_datasets(&path, &opts.convert_args).await {
+            datasets.append(&mut ds_vec);
+        }
+    }
+    datasets.sort_by(|a, b| a.meta.symbol.cmp(&b.meta.symbol));
+    let panel = Panel::new(&datasets, opts.field, opts.fill)?;
+    panel.write_csv(output_writer(output)?)?;
+    info!(
+        "wrote {} symbols over {} rows to {}",
+        panel.symbols.len(),
+        panel.rows.len(),
+        output
+    );
+    Ok(())
+}
+
+/// stream every json in a directory into one long csv at `output`, see [dialect::CsvSink]
+async fn convert_to_combined(opts: &ConvertOpts, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sink = Combined::new(output_writer(output)?, &opts.convert_args)?;
+    let mut count = 0;
+    for path in walk_dir(&opts.input_dir, opts.recursive).filter(|path| is_input(path, opts.convert_args.from)) {
+        for ds in load_datasets(&path, &opts.convert_args).await.unwrap_or_default() {
+            sink.write(&ds)?;
+            count += 1;
+        }
+    }
+    sink.close()?;
+    info!("combined {} datasets into {}", count, output);
+    Ok(())
+}
+
+/// copy every dataset under the input dir into a postgres table
+async fn load_postgres(opts: &LoadPostgresOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut loader = postgres::Loader::connect(&opts.url, &opts.table, opts.hypertable).await?;
+    let mut count = 0;
+    for path in walk_dir(&opts.input_dir, opts.recursive).filter(|path| is_input(path, opts.convert_args.from)) {
+        for ds in load_datasets(&path, &opts.convert_args).await.unwrap_or_default() {
+            match loader.load(&ds).await {
+                Ok(bars) => count += bars,
+                Err(err) => error!("failed to load {} from {:?} with {:?}", ds.meta.symbol, path, err),
+            }
+        }
+    }
+    info!("loaded {} bars into {}", count, opts.table);
+    Ok(())
+}
+
+/// post every dataset under the input dir to an InfluxDB write endpoint
+async fn push_to_influx(opts: &ConvertOpts, uri: &hyper::Uri) -> Result<(), Box<dyn std::error::Error>> {
+    let client = http::make_client();
+    let token = std::env::var("INFLUX_TOKEN").ok();
+    let mut count = 0;
+    for path in walk_dir(&opts.input_dir, opts.recursive).filter(|path| is_input(path, opts.convert_args.from)) {
+        for ds in load_datasets(&path, &opts.convert_args).await.unwrap_or_default() {
+            match influx::push(&client, uri, token.as_deref(), &ds).await {
+                Ok(lines) => count += lines,
+                Err(err) => error!("failed to push {} from {:?} with {:?}", ds.meta.symbol, path, err),
+            }
+        }
+    }
+    info!("pushed {} lines to {}", count, uri);
+    Ok(())
+}
+
+/// a file at `output`, or stdout for `-`
+fn output_writer(output: &str) -> std::io::Result<Box<dyn Write + Send>> {
+    if output == "-" {
+        Ok(Box::new(BufWriter::new(stdout())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(output)?)))
+    }
+}
+
+/// a single output that many datasets are streamed into
+enum Combined<W: Write + Send> {
+    Csv(CsvSink<W>),
+    Jsonl(W),
+    Influx(W),
+    Parquet(ParquetSink<W>),
+    Arrow(FeatherSink<W>),
+    Xlsx(Box<XlsxSink<W>>),
+}
+
+impl<W: Write + Send> Combined<W> {
+    fn new(writer: W, args: &ConvertArgs) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match args.format {
+            Format::Csv => Combined::Csv(CsvSink::new(writer, args.csv.clone(), true)),
+            Format::Jsonl => Combined::Jsonl(writer),
+            Format::Influx => Combined::Influx(writer),
+            Format::Parquet => Combined::Parquet(ParquetSink::try_new(writer, "UTC", args.compression)?),
+            Format::Arrow => Combined::Arrow(FeatherSink::new(writer, "UTC")),
+            Format::Xlsx => Combined::Xlsx(Box::new(XlsxSink::new(writer)?)),
+        })
+    }
+
+    fn write(&mut self, ds: &DataSet) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Combined::Csv(sink) => sink.write(ds),
+            Combined::Jsonl(writer) => write_tagged_jsonl(ds, writer),
+            Combined::Influx(writer) => influx::write_lines(ds, writer),
+            Combined::Parquet(sink) => sink.write(ds),
+            Combined::Arrow(sink) => sink.write(ds),
+            Combined::Xlsx(sink) => sink.write(ds),
+        }
+    }
+
+    fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Combined::Csv(sink) => sink.close(),
+            Combined::Jsonl(mut writer) | Combined::Influx(mut writer) => Ok(writer.flush()?),
+            Combined::Parquet(sink) => sink.close(),
+            Combined::Arrow(sink) => sink.close(),
+            Combined::Xlsx(sink) => sink.close(),
+        }
+    }
+}
+
+/// upsert the datasets of every json at `paths` into the SQLite database at `path`
+async fn upsert_to_sqlite<I: IntoIterator<Item = PathBuf>>(
+    paths: I,
+    path: &Path,
+    args: &ConvertArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = Store::open(path)?;
+    let mut count = 0;
+    for json in paths {
+        for ds in load_datasets(&json, args).await.unwrap_or_default() {
+            count += store.upsert(&ds)?;
+        }
+    }
+    info!("upserted {} bars into {:?}", count, path);
+    Ok(())
+}
+
+/// report the bars of every json in a directory whose adjclose cannot be reproduced
+fn verify_adjusted(opts: &VerifyAdjustedOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for path in walk_dir(&opts.input_dir, opts.recursive).filter(|path| is_input(path, opts.from)) {
+        match load_chart(&path, &opts.csv) {
+            Ok(chart_wrapper) => {
+                let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+                for ds in ds_vec.iter() {
+                    let mismatches = ds.verify_adjclose(opts.splits, opts.tolerance);
+                    info!(
+                        "{} has {} mismatched bars in {:?}",
+                        ds.meta.symbol,
+                        mismatches.len(),
+                        path
+                    );
+                    for m in mismatches.iter() {
+                        wtr.serialize(m)?;
+                    }
+                }
+            }
+            Err(err) => error!("failed to load {:?} with {:?}", path, err),
+        }
+    }
+    wtr.flush()?;
     Ok(())
 }
 
+/// validate every json in a directory, returns whether any error is found
+fn validate(opts: &ValidateOpts) -> Result<bool, Box<dyn std::error::Error>> {
+    let rules = match opts.rules.as_ref() {
+        Some(path) => validate::load_rules(path)?,
+        None => Default::default(),
+    };
+    let mut failed = false;
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    for path in walk_dir(&opts.input_dir, opts.recursive).filter(|path| is_input(path, opts.from)) {
+        match load_chart(&path, &opts.csv) {
+            Ok(chart_wrapper) => {
+                let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+                for ds in ds_vec.iter() {
+                    let violations = ds.validate(&rules);
+                    let errors = violations.iter().filter(|v| v.severity == Severity::Error).count();
+                    info!(
+                        "{} has {} violations and {} errors in {:?}",
+                        ds.meta.symbol,
+                        violations.len(),
+                        errors,
+                        path
+                    );
+                    failed |= errors > 0;
+                    for v in violations.iter() {
+                        wtr.serialize(v)?;
+                    }
+                }
+            }
+            Err(err) => {
+                // an input that cannot be checked fails the run as much as one with errors
+                error!("failed to load {:?} with {:?}", path, err);
+                failed = true;
+            }
+        }
+    }
+    wtr.flush()?;
+    Ok(failed)
+}
+
+/// download every symbol into memory and stream its records to stdout, upserting them into the
+/// store as well when one is given
+async fn download_to_stdout(opts: &DownloadOpts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sink = Combined::new(output_writer("-")?, &opts.convert_args)?;
+    let mut store = match opts.store.as_ref() {
+        Some(StoreUrl(path)) => Some(Store::open(path)?),
+        None => None,
+    };
+    let client = http::make_client();
+    for symbol in opts.symbols.iter() {
+        tokio::time::sleep(opts.rate.0).await;
+        let mut ds_vec = match http::fetch(&client, opts, symbol).await {
+            Ok(ds_vec) => ds_vec,
+            Err(err) => {
+                error!("failed to download {} with {:?}", symbol, err);
+                continue;
+            }
+        };
+        prepare(&mut ds_vec, &opts.convert_args, None).await;
+        for ds in ds_vec.iter() {
+            sink.write(ds)?;
+            if let Some(store) = store.as_mut() {
+                store.upsert(ds)?;
+            }
+        }
+    }
+    sink.close()
+}
+
+/// download and optionally convert to CSVs
+async fn download(opts: &DownloadOpts) {
+    if let Some(start) = opts.start {
+        if let Some(end) = opts.end {
+            if start >= end {
+                panic!("start date is greater or equal to end date")
+            }
+        }
+    }
+    if opts.output_dir == "-" {
+        if let Err(err) = download_to_stdout(opts).await {
+            error!("failed to stream to stdout with {:?}", err);
+        }
+        return;
+    }
+    let results = http::download(opts).await;
+    let downloaded: Vec<PathBuf> = results
+        .into_iter()
+        .zip(opts.symbols.iter())
+        .filter_map(|((p, res), symbol)| {
+            res.ok()?;
+            match load_from_json(p.to_str().unwrap()) {
+                Ok(chart_wrapper) => {
+                    let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+                    let request = Request::new(opts, symbol);
+                    let outputs = sibling_paths(&p, ds_vec.len(), ".json");
+                    write_sidecars(&p, &ds_vec, &outputs, Adjustment::None, Some(&request));
+                }
+                Err(err) => error!("failed to load json from {:?} with {:?}", p, err),
+            }
+            Some(p)
+        })
+        .collect();
+    if !opts.convert && opts.store.is_none() {
+        return;
+    }
+    let mut store = match opts.store.as_ref().map(|StoreUrl(path)| (Store::open(path), path)) {
+        Some((Ok(store), path)) => Some((store, path, 0)),
+        Some((Err(err), path)) => {
+            error!("failed to store into {:?} with {:?}", path, err);
+            None
+        }
+        None => None,
+    };
+    // each download is repaired once and the same datasets are both converted and stored
+    for p in downloaded.iter() {
+        let ds_vec = match load_datasets(p, &opts.convert_args).await {
+            Some(ds_vec) => ds_vec,
+            None => continue,
+        };
+        if let Some((store, path, count)) = store.as_mut() {
+            for ds in ds_vec.iter() {
+                match store.upsert(ds) {
+                    Ok(bars) => *count += bars,
+                    Err(err) => error!("failed to store {} into {:?} with {:?}", ds.meta.symbol, path, err),
+                }
+            }
+        }
+        if opts.convert {
+            write_converted(p, ds_vec, &opts.convert_args, None);
+        }
+    }
+    if let Some((_, path, count)) = store {
+        info!("upserted {} bars into {:?}", count, path);
+    }
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
     let opts = options::parse();
     match opts.subcmd {
-        SubCommand::Download(opts) => {
-            if let Some(start) = opts.start {
-                if let Some(end) = opts.end {
-                    if start >= end {
-                        panic!("start date is greater or equal to end date")
-                    }
+        SubCommand::Download(opts) => download(&opts).await,
+        SubCommand::Convert(opts) => match (
+            opts.panel.as_ref(),
+            opts.combine.as_ref(),
+            opts.to_sqlite.as_ref(),
+            opts.push.as_ref(),
+        ) {
+            (_, _, _, Some(uri)) => {
+                if let Err(err) = push_to_influx(&opts, uri).await {
+                    error!("failed to push to {} with {:?}", uri, err);
                 }
             }
-            let results = http::download(&opts).await;
-            if opts.convert {
-                results.into_iter().for_each(|(p, res)| {
-                    if res.is_ok() {
-                        convert(p);
-                    }
-                });
+            (_, _, Some(path), _) => {
+                let from = opts.convert_args.from;
+                let paths = walk_dir(&opts.input_dir, opts.recursive).filter(move |path| is_input(path, from));
+                if let Err(err) = upsert_to_sqlite(paths, Path::new(path), &opts.convert_args).await {
+                    error!("failed to store into {} with {:?}", path, err);
+                }
+            }
+            (Some(output), _, _, _) => {
+                if let Err(err) = convert_to_panel(&opts, output).await {
+                    error!("failed to write panel {} with {:?}", output, err);
+                }
+            }
+            (_, Some(output), _, _) => {
+                if let Err(err) = convert_to_combined(&opts, output).await {
+                    error!("failed to combine into {} with {:?}", output, err);
+                }
+            }
+            _ => {
+                if let Err(err) = convert_to_csv(&opts.input_dir, opts.recursive, &opts.convert_args, None).await {
+                    error!("failed to walk dir {} with {:?}", opts.input_dir, err);
+                }
+            }
+        },
+        SubCommand::News(opts) => {
+            if let Err(err) = news::news(&opts).await {
+                error!("failed to write news to {} with {:?}", opts.output, err);
             }
         }
-        SubCommand::Convert(opts) => {
-            if let Err(err) = convert_to_csv(&opts.input_dir, opts.recursive) {
+        SubCommand::Screen(opts) => match screener::screen(&opts).await {
+            Ok(symbols) if opts.download => download(&opts.download_opts(symbols)).await,
+            Ok(symbols) => symbols.iter().for_each(|s| println!("{}", s)),
+            Err(err) => error!("failed to run screeners with {:?}", err),
+        },
+        SubCommand::Resample(opts) => {
+            if let Err(err) = convert_to_csv(&opts.input_dir, opts.recursive, &opts.convert_args, Some(opts.to)).await {
                 error!("failed to walk dir {} with {:?}", opts.input_dir, err);
             }
         }
+        SubCommand::Validate(opts) => match validate(&opts) {
+            Ok(false) => {}
+            Ok(true) => std::process::exit(1),
+            Err(err) => {
+                error!("failed to validate {} with {:?}", opts.input_dir, err);
+                std::process::exit(2);
+            }
+        },
+        SubCommand::VerifyAdjusted(opts) => {
+            if let Err(err) = verify_adjusted(&opts) {
+                error!("failed to verify adjusted close in {} with {:?}", opts.input_dir, err);
+            }
+        }
+        SubCommand::Merge(opts) => {
+            let paths = walk_dir(&opts.input_dir, opts.recursive)
+                .filter(|path| is_input(path, opts.from))
+                .collect::<Vec<_>>();
+            if let Err(err) = merge::merge(paths, &opts) {
+                error!("failed to merge {} with {:?}", opts.input_dir, err);
+            }
+        }
+        SubCommand::LoadPostgres(opts) => {
+            if let Err(err) = load_postgres(&opts).await {
+                error!("failed to load {} into postgres with {:?}", opts.input_dir, err);
+            }
+        }
+        SubCommand::Stream(opts) => {
+            if let Err(err) = stream::stream(&opts).await {
+                error!("failed to stream {:?} with {:?}", opts.symbols, err);
+            }
+        }
     };
 }
@@ -0,0 +1,499 @@
+use crate::options::{InputFormat, MergeOpts as Opts};
+use crate::sidecar::{load_sidecar, sidecar_path, write_sidecar, Sidecar};
+use crate::v8chart::{
+    load_chart, write_to_csv, write_to_jsonl, AdjClose, Chart, ChartWrapper, DataSet, Event, Indicators, V8Result,
+    OHLCV,
+};
+
+use chrono::DateTime;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fs::{remove_file, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// one bar of a raw result
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    volume: Option<u64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    open: Option<f64>,
+    close: Option<f64>,
+    adjclose: Option<f64>,
+}
+
+impl Row {
+    /// multiply open, high, low and close by `ratio` and divide the volume by it, see
+    /// [crate::v8chart::Record::scale]
+    fn scale(&mut self, ratio: f64) {
+        for price in [&mut self.open, &mut self.high, &mut self.low, &mut self.close].iter_mut() {
+            if let Some(p) = price.as_mut() {
+                *p *= ratio;
+            }
+        }
+        self.volume = self.volume.map(|v| (v as f64 / ratio).round() as u64);
+    }
+}
+
+fn rows(result: &V8Result) -> BTreeMap<i64, Row> {
+    let empty = OHLCV::default();
+    let quote = result.indicators.quote.first().unwrap_or(&empty);
+    let adjclose = result.indicators.adjclose.first().map(|a| &a.adjclose);
+    result
+        .timestamp
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let get = |v: &Vec<Option<f64>>| v.get(i).copied().flatten();
+            let row = Row {
+                volume: quote.volume.get(i).copied().flatten(),
+                high: get(&quote.high),
+                low: get(&quote.low),
+                open: get(&quote.open),
+                close: get(&quote.close),
+                adjclose: adjclose.and_then(get),
+            };
+            (*t, row)
+        })
+        .collect()
+}
+
+/// union of two event maps keyed by timestamp, the newer one wins
+fn union<T>(older: Option<HashMap<String, T>>, newer: Option<HashMap<String, T>>) -> Option<HashMap<String, T>> {
+    match (older, newer) {
+        (Some(mut older), Some(newer)) => {
+            older.extend(newer);
+            Some(older)
+        }
+        (older, newer) => newer.or(older),
+    }
+}
+
+impl V8Result {
+    /// Union the bars and events of this download with a newer one of the same symbol and
+    /// interval. The newer one wins on every timestamp and event present in both and its meta is
+    /// kept. As a later dividend changes every earlier adjclose, the adjclose of the older bars
+    /// before the newer download starts is rescaled to join it at their first common bar. With
+    /// `adjusted` inputs, whose prices are back-adjusted as well, so are their prices and volumes.
+    pub fn merge(self, newer: V8Result, adjusted: bool) -> V8Result {
+        let mut bars = rows(&self);
+        let newer_bars = rows(&newer);
+        let ratio = newer_bars.iter().find_map(|(t, row)| {
+            let old = bars.get(t).and_then(|old| old.adjclose)?;
+            let new = row.adjclose?;
+            if old != 0.0 {
+                Some(new / old)
+            } else {
+                None
+            }
+        });
+        if let (Some(ratio), Some(first)) = (ratio, newer_bars.keys().next()) {
+            for (_, row) in bars.range_mut(..first) {
+                row.adjclose = row.adjclose.map(|a| a * ratio);
+                if adjusted {
+                    row.scale(ratio);
+                }
+            }
+        }
+        bars.extend(newer_bars);
+
+        let events = match (self.events, newer.events) {
+            (Some(older), Some(newer)) => Some(Event {
+                splits: union(older.splits, newer.splits),
+                dividends: union(older.dividends, newer.dividends),
+                capital_gains: union(older.capital_gains, newer.capital_gains),
+            }),
+            (older, newer) => newer.or(older),
+        };
+        let column = |f: fn(&Row) -> Option<f64>| bars.values().map(f).collect::<Vec<_>>();
+        let adjclose = if bars.values().any(|r| r.adjclose.is_some()) {
+            vec![AdjClose {
+                adjclose: column(|r| r.adjclose),
+            }]
+        } else {
+            Vec::new()
+        };
+        V8Result {
+            meta: newer.meta,
+            timestamp: bars.keys().copied().collect(),
+            indicators: Indicators {
+                quote: vec![OHLCV {
+                    volume: bars.values().map(|r| r.volume).collect(),
+                    high: column(|r| r.high),
+                    close: column(|r| r.close),
+                    low: column(|r| r.low),
+                    open: column(|r| r.open),
+                }],
+                adjclose,
+            },
+            events,
+        }
+    }
+}
+
+/// the `{start}_{end}` of a file named by `download`, `start` is `init` without a start date
+fn span(path: &Path) -> Option<(String, String)> {
+    let stem = path.file_stem()?.to_str()?;
+    let mut parts = stem.rsplitn(3, '_');
+    let (end, start) = (parts.next()?, parts.next()?);
+    parts.next()?;
+    let is_date = |s: &str| s.len() == 8 && s.chars().all(|c| c.is_ascii_digit());
+    if is_date(end) && (start == "init" || is_date(start)) {
+        Some((start.to_string(), end.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Name of a merged file in the style of `download` from the earliest start and the latest end of
+/// its inputs, with the interval added when it is not daily. The dates of the first and last bar
+/// are used when an input is not named by `download`
+fn file_name(result: &V8Result, inputs: &[PathBuf], extension: &str) -> Option<String> {
+    let spans: Option<Vec<(String, String)>> = inputs.iter().map(|p| span(p)).collect();
+    let (start, end) = match spans.filter(|spans| !spans.is_empty()) {
+        Some(spans) => {
+            let start = if spans.iter().any(|(start, _)| start == "init") {
+                "init".to_string()
+            } else {
+                spans.iter().map(|(start, _)| start.clone()).min()?
+            };
+            (start, spans.into_iter().map(|(_, end)| end).max()?)
+        }
+        None => {
+            let offset = result.meta.offset();
+            let date = |t: i64| {
+                Some(
+                    DateTime::from_timestamp(t, 0)?
+                        .with_timezone(&offset)
+                        .format("%Y%m%d")
+                        .to_string(),
+                )
+            };
+            (date(*result.timestamp.first()?)?, date(*result.timestamp.last()?)?)
+        }
+    };
+    let interval = match result.meta.data_granularity.as_str() {
+        "1d" | "" => String::new(),
+        granularity => format!("_{}", granularity),
+    };
+    Some(format!(
+        "{}{}_{}_{}.{}",
+        result.meta.symbol, interval, start, end, extension
+    ))
+}
+
+/// a result with when and where it was downloaded
+type Download = ((i64, SystemTime), PathBuf, V8Result);
+
+/// when a file was downloaded, by the market time in its meta and then by modification time
+fn downloaded_at(result: &V8Result, path: &Path) -> (i64, SystemTime) {
+    let modified = path
+        .metadata()
+        .and_then(|m| m.modified())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    (result.meta.regular_market_time.unwrap_or(0), modified)
+}
+
+/// Entry function that takes a [crate::options::MergeOpts] and merges the JSONs at `paths` into
+/// one per symbol and interval, the newest download wins. Returns the merged files
+pub fn merge<I: IntoIterator<Item = PathBuf>>(paths: I, opts: &Opts) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut groups: BTreeMap<(String, String), Vec<Download>> = BTreeMap::new();
+    for path in paths {
+        let chart = match load_chart(&path, &opts.csv) {
+            Ok(chart_wrapper) => chart_wrapper.chart,
+            Err(err) => {
+                error!("failed to load {:?} with {:?}", path, err);
+                continue;
+            }
+        };
+        if let Some(err) = chart.error.as_ref() {
+            warn!(
+                "skipping {:?} as it contains an error {}: {}",
+                path, err.code, err.description
+            );
+            continue;
+        }
+        for result in chart.result.unwrap_or_default() {
+            let key = (result.meta.symbol.clone(), result.meta.data_granularity.clone());
+            groups
+                .entry(key)
+                .or_default()
+                .push((downloaded_at(&result, &path), path.clone(), result));
+        }
+    }
+
+    let output_dir = Path::new(opts.output_dir.as_ref().unwrap_or(&opts.input_dir));
+    let mut inputs = HashSet::new();
+    let mut outputs = Vec::new();
+    for ((symbol, interval), mut downloads) in groups {
+        downloads.sort_by_key(|(at, _, _)| *at);
+        let count = downloads.len();
+        if count == 1 {
+            debug!("leaving {} {} as there is a single download", symbol, interval);
+            continue;
+        }
+        // outputs carry on the adjustment of their inputs, which must agree
+        let sidecars: Vec<Sidecar> = downloads
+            .iter()
+            .filter_map(|(_, path, _)| load_sidecar(sidecar_path(path)).ok())
+            .collect();
+        let adjusts: HashSet<&str> = sidecars.iter().map(|s| s.adjust.as_str()).collect();
+        if adjusts.len() > 1 {
+            warn!(
+                "skipping {} {} as its inputs are adjusted differently: {:?}",
+                symbol, interval, adjusts
+            );
+            continue;
+        }
+        let adjusted = adjusts.iter().any(|adjust| *adjust != "none");
+        let mut merged: Option<V8Result> = None;
+        let mut group_inputs = Vec::new();
+        for (_, path, result) in downloads {
+            group_inputs.push(path);
+            merged = Some(match merged {
+                Some(older) => older.merge(result, adjusted),
+                None => result,
+            });
+        }
+        let merged = merged.unwrap();
+        let extension = match opts.from {
+            InputFormat::Json => "json",
+            InputFormat::Csv => "csv",
+            InputFormat::Jsonl => "jsonl",
+        };
+        let name = match file_name(&merged, &group_inputs, extension) {
+            Some(name) => name,
+            None => {
+                warn!("skipping {} {} as there is no bar", symbol, interval);
+                continue;
+            }
+        };
+        let path = output_dir.join(name);
+        let chart = Chart {
+            result: Some(vec![merged]),
+            error: None,
+        };
+        // outputs are merged into an output of the same format with a sidecar of its own
+        let ds_vec: Vec<DataSet> = if opts.from == InputFormat::Json {
+            let chart_wrapper = ChartWrapper { chart };
+            serde_json::to_writer(BufWriter::new(File::create(&path)?), &chart_wrapper)?;
+            chart_wrapper.chart.into()
+        } else {
+            let ds_vec: Vec<DataSet> = chart.into();
+            match opts.from {
+                InputFormat::Csv => write_to_csv(&ds_vec[0], &path, &opts.csv)?,
+                _ => write_to_jsonl(&ds_vec[0], &path)?,
+            }
+            ds_vec
+        };
+        let mut sidecar = Sidecar::new(&ds_vec[0], &path);
+        if let Some(newest) = sidecars.last() {
+            sidecar.inherit(newest.clone());
+            sidecar.adjust = newest.adjust.clone();
+        }
+        write_sidecar(&sidecar, sidecar_path(&path))?;
+        info!("merged {} downloads of {} {} into {:?}", count, symbol, interval, path);
+        inputs.extend(group_inputs);
+        outputs.push(path);
+    }
+
+    if opts.delete {
+        for path in inputs.iter().filter(|p| !outputs.contains(p)) {
+            match remove_file(path) {
+                Ok(_) => info!("deleted {:?}", path),
+                Err(err) => error!("failed to delete {:?} with {:?}", path, err),
+            }
+            let _ = remove_file(sidecar_path(path));
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dialect::Delimiter;
+    use crate::options::CsvArgs;
+    use crate::v8chart::{load_from_csv, load_from_json};
+    use rand::{distributions::Alphanumeric, thread_rng, Rng};
+    use std::fs::{copy, create_dir, read_dir, remove_dir_all};
+
+    fn load(path: &str) -> V8Result {
+        load_from_json(path).unwrap().chart.result.unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_merge_results() {
+        let older = load("assets/^GSPC_20210104_20210108.json");
+        let newer = load("assets/^GSPC_20210106_20210111.json");
+        let merged = older.merge(newer, false);
+        assert_eq!(merged.timestamp.len(), 6);
+        assert_eq!(merged.meta.regular_market_time, Some(1611694800));
+        assert_eq!(file_name(&merged, &[], "json").unwrap(), "^GSPC_20210104_20210111.json");
+        // the requested range of the inputs rather than their bars
+        let inputs = [
+            PathBuf::from("^GSPC_20210101_20210108.json"),
+            PathBuf::from("x/^GSPC_init_20210110.json"),
+        ];
+        assert_eq!(file_name(&merged, &inputs, "csv").unwrap(), "^GSPC_init_20210110.csv");
+        assert_eq!(span(Path::new("^GSPC_1mo.json")), None);
+        let ds_vec: Vec<DataSet> = Chart {
+            result: Some(vec![merged]),
+            error: None,
+        }
+        .into();
+        let records = &ds_vec[0].records;
+        assert_eq!(records[0].close, Some(3700.64990234375));
+        assert_eq!(records[3].close, Some(3803.8000390625));
+        assert_eq!(records[5].volume, Some(4450500000));
+        assert_eq!(records[0].adjclose, records[0].close);
+    }
+
+    #[test]
+    fn test_merge_adjusted() {
+        // a distribution after the newer download ends scales all of its bars before it by 0.99
+        let merged = |adjusted: bool| {
+            let older = load("assets/^GSPC_20210104_20210108.json");
+            let mut newer = load("assets/^GSPC_20210106_20210111.json");
+            let quote = &mut newer.indicators.quote[0];
+            for price in quote
+                .close
+                .iter_mut()
+                .chain(newer.indicators.adjclose[0].adjclose.iter_mut())
+            {
+                *price = price.map(|p| p * 0.99);
+            }
+            let ds_vec: Vec<DataSet> = Chart {
+                result: Some(vec![older.merge(newer, adjusted)]),
+                error: None,
+            }
+            .into();
+            ds_vec.into_iter().next().unwrap()
+        };
+        let raw = merged(false);
+        assert_eq!(raw.records[0].close, Some(3700.64990234375));
+        assert!((raw.records[0].adjclose.unwrap() - 3700.64990234375 * 0.99).abs() < 1e-6);
+        // adjusted prices join the newer download rather than step at its first bar
+        let adjusted = merged(true);
+        assert_eq!(adjusted.records[0].close, adjusted.records[0].adjclose);
+        assert!((adjusted.records[0].open.unwrap() - raw.records[0].open.unwrap() * 0.99).abs() < 1e-6);
+        assert_eq!(
+            adjusted.records[0].volume,
+            raw.records[0].volume.map(|v| (v as f64 / 0.99).round() as u64)
+        );
+        assert_eq!(adjusted.records[2].close, raw.records[2].close);
+    }
+
+    #[test]
+    fn test_merge_dir() {
+        let prefix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+        let dir = std::env::temp_dir().join(prefix);
+        create_dir(&dir).unwrap();
+        for name in [
+            "^GSPC_20210104_20210108.json",
+            "^GSPC_20210106_20210111.json",
+            "AUDUSD=X_20210104_20210106.json",
+        ]
+        .iter()
+        {
+            copy(Path::new("assets").join(name), dir.join(name)).unwrap();
+        }
+        let input = dir.join("^GSPC_20210104_20210108.json");
+        let ds_vec: Vec<DataSet> = load_from_json(input.to_str().unwrap()).unwrap().chart.into();
+        write_sidecar(&Sidecar::new(&ds_vec[0], &input), sidecar_path(&input)).unwrap();
+        let opts = Opts {
+            input_dir: dir.to_str().unwrap().to_string(),
+            recursive: false,
+            output_dir: None,
+            delete: true,
+            from: InputFormat::Json,
+            csv: CsvArgs::default(),
+        };
+        let paths = read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| !p.to_string_lossy().ends_with(".meta.json"))
+            .collect::<Vec<_>>();
+        let outputs = merge(paths, &opts).unwrap();
+        // a single download is left as it is
+        assert_eq!(outputs.len(), 1);
+        let mut names: Vec<String> = read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "AUDUSD=X_20210104_20210106.json",
+                "^GSPC_20210104_20210111.json",
+                "^GSPC_20210104_20210111.json.meta.json"
+            ]
+        );
+        assert_eq!(load_sidecar(sidecar_path(&outputs[0])).unwrap().bars, 6);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_csv() {
+        let prefix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+        let dir = std::env::temp_dir().join(prefix);
+        create_dir(&dir).unwrap();
+        let csv = CsvArgs {
+            delimiter: Delimiter(b';'),
+            ..CsvArgs::default()
+        };
+        let write = |name: &str, adjust: &str| {
+            let source = Path::new("assets").join(format!("{}.json", name));
+            let ds_vec: Vec<DataSet> = load_from_json(source.to_str().unwrap()).unwrap().chart.into();
+            let path = dir.join(format!("{}.csv", name));
+            write_to_csv(&ds_vec[0], &path, &csv).unwrap();
+            let mut sidecar = Sidecar::new(&ds_vec[0], &source);
+            sidecar.adjust = adjust.to_string();
+            write_sidecar(&sidecar, sidecar_path(&path)).unwrap();
+            path
+        };
+        let mut opts = Opts {
+            input_dir: dir.to_str().unwrap().to_string(),
+            recursive: false,
+            output_dir: None,
+            delete: true,
+            from: InputFormat::Csv,
+            csv: csv.clone(),
+        };
+
+        // inputs adjusted differently are not merged
+        let paths = vec![
+            write("^GSPC_20210104_20210108", "all"),
+            write("^GSPC_20210106_20210111", "none"),
+        ];
+        assert!(merge(paths, &opts).unwrap().is_empty());
+
+        let paths = vec![
+            write("^GSPC_20210104_20210108", "all"),
+            write("^GSPC_20210106_20210111", "all"),
+        ];
+        opts.output_dir = Some(dir.join("merged").to_str().unwrap().to_string());
+        create_dir(dir.join("merged")).unwrap();
+        let outputs = merge(paths, &opts).unwrap();
+        assert_eq!(outputs, vec![dir.join("merged").join("^GSPC_20210104_20210111.csv")]);
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        let ds = load_from_csv(&outputs[0], &csv).unwrap();
+        assert_eq!(ds.records.len(), 6);
+        assert_eq!(ds.meta.symbol, "^GSPC");
+        assert_eq!(load_sidecar(sidecar_path(&outputs[0])).unwrap().adjust, "all");
+        remove_dir_all(dir).unwrap();
+    }
+}
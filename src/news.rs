@@ -0,0 +1,214 @@
+use crate::http::{get_bytes, make_client, Result};
+use crate::options::NewsOpts as Opts;
+
+use chrono::{DateTime, Utc};
+use csv::{ReaderBuilder, WriterBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+use tokio::time::sleep;
+
+/// A news item as returned by the yahoo search endpoint
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NewsItem {
+    pub uuid: String,
+    pub title: String,
+    pub publisher: String,
+    pub link: String,
+    pub provider_publish_time: i64,
+    #[serde(default)]
+    pub related_tickers: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchResponse {
+    #[serde(default)]
+    pub news: Vec<NewsItem>,
+}
+
+/// A flattened headline that is written to JSONL or CSV
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Headline {
+    pub uuid: String,
+    pub symbol: String,
+    pub title: String,
+    pub publisher: String,
+    pub link: String,
+    pub publish_time: DateTime<Utc>,
+    /// comma separated tickers
+    pub related_tickers: String,
+}
+
+impl Headline {
+    /// None when the publish time is out of range
+    fn new(symbol: &str, item: NewsItem) -> Option<Self> {
+        let publish_time = match DateTime::from_timestamp(item.provider_publish_time, 0) {
+            Some(publish_time) => publish_time,
+            None => {
+                warn!(
+                    "skipping headline {} of {} published at out of range {}",
+                    item.uuid, symbol, item.provider_publish_time
+                );
+                return None;
+            }
+        };
+        Some(Headline {
+            uuid: item.uuid,
+            symbol: symbol.to_owned(),
+            title: item.title,
+            publisher: item.publisher,
+            link: item.link,
+            publish_time,
+            related_tickers: item.related_tickers.join(","),
+        })
+    }
+}
+
+/// compose a search API request URI that asks for news only
+fn make_uri(base: &str, symbol: &str, count: u32) -> hyper::Uri {
+    let url = url::Url::parse_with_params(
+        base,
+        &[
+            ("q", symbol.to_owned()),
+            ("quotesCount", "0".to_string()),
+            ("newsCount", count.to_string()),
+        ],
+    )
+    .unwrap();
+    debug!("{}", url.as_str());
+    url.into_string().parse().unwrap()
+}
+
+/// parse the body of a search response into headlines for `symbol`
+pub fn parse_news(body: &[u8], symbol: &str) -> Result<Vec<Headline>> {
+    let resp: SearchResponse = serde_json::from_slice(body)?;
+    Ok(resp
+        .news
+        .into_iter()
+        .filter_map(|item| Headline::new(symbol, item))
+        .collect())
+}
+
+/// collect the uuids of the headlines already written to `path`
+fn load_seen(path: &Path, format: &str) -> Result<HashSet<String>> {
+    let mut seen = HashSet::new();
+    if !path.exists() {
+        return Ok(seen);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    match format {
+        "csv" => {
+            for h in ReaderBuilder::new().from_reader(reader).deserialize::<Headline>() {
+                seen.insert(h?.uuid);
+            }
+        }
+        _ => {
+            for line in reader.lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    seen.insert(serde_json::from_str::<Headline>(&line)?.uuid);
+                }
+            }
+        }
+    }
+    Ok(seen)
+}
+
+/// append headlines to `path` skipping those already in the file, returns the number written
+pub fn append_headlines<P: AsRef<Path>>(path: P, format: &str, headlines: Vec<Headline>) -> Result<usize> {
+    let path = path.as_ref();
+    let mut seen = load_seen(path, format)?;
+    let is_new = path.metadata().map_or(true, |m| m.len() == 0);
+    let fresh: Vec<Headline> = headlines.into_iter().filter(|h| seen.insert(h.uuid.clone())).collect();
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        "csv" => {
+            let mut wtr = WriterBuilder::new().has_headers(is_new).from_writer(writer);
+            for h in fresh.iter() {
+                wtr.serialize(h)?;
+            }
+            wtr.flush()?;
+        }
+        _ => {
+            for h in fresh.iter() {
+                serde_json::to_writer(&mut writer, h)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(fresh.len())
+}
+
+/// Entry function that takes a [crate::options::NewsOpts] to fetch headlines for every symbol
+/// and append the unseen ones to the output file
+pub async fn news(opts: &Opts) -> Result<usize> {
+    let client = make_client();
+    let mut headlines = Vec::new();
+    for symb in opts.symbols.iter() {
+        let uri = make_uri(&opts.base_url, symb, opts.count);
+        match get_bytes(&client, uri, symb).await {
+            Ok(body) => match parse_news(&body, symb) {
+                Ok(mut items) => headlines.append(&mut items),
+                Err(err) => error!("failed to parse news for {} with {:?}", symb, err),
+            },
+            Err(err) => error!("encounter error: {:?}", err),
+        }
+        sleep(opts.rate.0).await;
+    }
+    let written = append_headlines(&opts.output, &opts.format, headlines)?;
+    info!("have written {} new headlines to {}", written, opts.output);
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{distributions::Alphanumeric, thread_rng, Rng};
+    use std::fs::{read_to_string, remove_file};
+
+    fn temp_path() -> std::path::PathBuf {
+        let prefix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+        std::env::temp_dir().join(prefix)
+    }
+
+    #[test]
+    fn test_parse_news() {
+        let body = std::fs::read("assets/AAPL_news_20210126.json").unwrap();
+        let headlines = parse_news(&body, "AAPL").unwrap();
+        assert_eq!(headlines.len(), 3);
+        assert_eq!(headlines[0].symbol, "AAPL");
+        assert_eq!(headlines[0].related_tickers, "AAPL,MSFT");
+        assert_eq!(headlines[2].related_tickers, "");
+    }
+
+    #[test]
+    fn test_append_dedup() {
+        let body = std::fs::read("assets/AAPL_news_20210126.json").unwrap();
+        for format in ["jsonl", "csv"].iter() {
+            let path = temp_path();
+            assert_eq!(
+                append_headlines(&path, format, parse_news(&body, "AAPL").unwrap()).unwrap(),
+                3
+            );
+            assert_eq!(
+                append_headlines(&path, format, parse_news(&body, "AAPL").unwrap()).unwrap(),
+                0
+            );
+            let lines = read_to_string(&path).unwrap().lines().count();
+            assert_eq!(lines, if *format == "csv" { 4 } else { 3 });
+            let _ = remove_file(path);
+        }
+    }
+}
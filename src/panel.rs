@@ -0,0 +1,216 @@
+use crate::resample::Local;
+use crate::v8chart::{DataSet, Record};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use csv::Writer;
+use std::{collections::BTreeMap, error::Error, io::Write, str::FromStr};
+
+/// The field of a bar that goes into the cells of a panel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Open,
+    High,
+    Low,
+    Close,
+    AdjClose,
+    Volume,
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Field::Open),
+            "high" => Ok(Field::High),
+            "low" => Ok(Field::Low),
+            "close" => Ok(Field::Close),
+            "adjclose" => Ok(Field::AdjClose),
+            "volume" => Ok(Field::Volume),
+            _ => Err(format!("unknown field {}", s)),
+        }
+    }
+}
+
+impl Field {
+    fn get(self, r: &Record) -> Option<f64> {
+        match self {
+            Field::Open => r.open,
+            Field::High => r.high,
+            Field::Low => r.low,
+            Field::Close => r.close,
+            Field::AdjClose => r.adjclose,
+            Field::Volume => r.volume.map(|v| v as f64),
+        }
+    }
+}
+
+/// What to put in a cell where a symbol has no bar, e.g. when its exchange is closed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fill {
+    Blank,
+    /// the last value of the symbol, cells before its first bar are left blank
+    Forward,
+}
+
+impl FromStr for Fill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blank" => Ok(Fill::Blank),
+            "ffill" => Ok(Fill::Forward),
+            _ => Err(format!("unknown fill {}", s)),
+        }
+    }
+}
+
+/// A row of a panel. Daily and longer bars are keyed by their exchange-local date so that symbols
+/// from different timezones line up, intraday bars by their UTC time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    Date(NaiveDate),
+    Time(DateTime<Utc>),
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Key::Date(date) => write!(f, "{}", date),
+            Key::Time(time) => write!(f, "{}", time.to_rfc3339()),
+        }
+    }
+}
+
+pub(crate) fn is_intraday(ds: &DataSet) -> bool {
+    let granularity = ds.meta.data_granularity.as_str();
+    granularity.ends_with('h') || (granularity.ends_with('m') && !granularity.ends_with("mo"))
+}
+
+/// A matrix of one field with a row per timestamp and a column per symbol
+#[derive(Debug)]
+pub struct Panel {
+    pub symbols: Vec<String>,
+    pub rows: BTreeMap<Key, Vec<Option<f64>>>,
+}
+
+impl Panel {
+    /// Outer-join the datasets on their keys. A symbol that shows up in more than one dataset
+    /// shares a column and the later dataset wins on the same key. Daily and intraday datasets
+    /// cannot be mixed as their keys do not line up.
+    pub fn new(datasets: &[DataSet], field: Field, fill: Fill) -> Result<Panel, String> {
+        if let (Some(daily), Some(intraday)) = (
+            datasets.iter().find(|ds| !is_intraday(ds)),
+            datasets.iter().find(|ds| is_intraday(ds)),
+        ) {
+            return Err(format!(
+                "cannot mix daily {} {} with intraday {} {} in a panel",
+                daily.meta.symbol, daily.meta.data_granularity, intraday.meta.symbol, intraday.meta.data_granularity
+            ));
+        }
+        let mut symbols: Vec<String> = Vec::new();
+        let mut cells: Vec<(Key, usize, Option<f64>)> = Vec::new();
+        for ds in datasets.iter() {
+            let column = match symbols.iter().position(|s| *s == ds.meta.symbol) {
+                Some(column) => column,
+                None => {
+                    symbols.push(ds.meta.symbol.clone());
+                    symbols.len() - 1
+                }
+            };
+            let local = Local::new(ds);
+            let intraday = is_intraday(ds);
+            for r in ds.records.iter() {
+                let key = if intraday {
+                    Key::Time(r.timestamp.with_timezone(&Utc))
+                } else {
+                    Key::Date(local.to_local(&r.timestamp.naive_utc()).date())
+                };
+                cells.push((key, column, field.get(r)));
+            }
+        }
+
+        let mut rows: BTreeMap<Key, Vec<Option<f64>>> = BTreeMap::new();
+        for (key, column, value) in cells {
+            let row = rows.entry(key).or_insert_with(|| vec![None; symbols.len()]);
+            if value.is_some() || row[column].is_none() {
+                row[column] = value;
+            }
+        }
+        if fill == Fill::Forward {
+            let mut last = vec![None; symbols.len()];
+            for row in rows.values_mut() {
+                for (cell, last) in row.iter_mut().zip(last.iter_mut()) {
+                    match cell {
+                        Some(_) => *last = *cell,
+                        None => *cell = *last,
+                    }
+                }
+            }
+        }
+        Ok(Panel { symbols, rows })
+    }
+
+    /// write as csv with a `timestamp` column followed by a column per symbol, blank for a missing
+    /// value
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let mut wtr = Writer::from_writer(writer);
+        wtr.write_record(std::iter::once("timestamp").chain(self.symbols.iter().map(|s| s.as_str())))?;
+        for (key, row) in self.rows.iter() {
+            let cells = row.iter().map(|v| v.map_or(String::new(), |v| v.to_string()));
+            wtr.write_record(std::iter::once(key.to_string()).chain(cells))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_daily_panel() {
+        let datasets = vec![
+            load("assets/^GSPC_20210104_20210108.json"),
+            load("assets/BTC-USD_20210104_20210106.json"),
+        ];
+        let panel = Panel::new(&datasets, Field::AdjClose, Fill::Blank).unwrap();
+        assert_eq!(panel.symbols, vec!["^GSPC", "BTC-USD"]);
+        assert_eq!(panel.rows.len(), 4);
+        let mut buf = Vec::new();
+        panel.write_csv(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "timestamp,^GSPC,BTC-USD");
+        assert_eq!(lines[1], "2021-01-04,3700.64990234375,31971.9140625");
+        assert_eq!(lines[4], "2021-01-07,3803.7900390625,");
+
+        let panel = Panel::new(&datasets, Field::Volume, Fill::Forward).unwrap();
+        let last = panel.rows.values().last().unwrap();
+        assert_eq!(last[1], panel.rows.values().nth(1).unwrap()[1]);
+        assert_eq!(last[0], Some(5080870000.0));
+    }
+
+    #[test]
+    fn test_intraday_panel() {
+        let datasets = vec![load("assets/AAPL_5m_20210125.json")];
+        let panel = Panel::new(&datasets, Field::Close, Fill::Forward).unwrap();
+        assert!(panel.rows.keys().all(|k| matches!(k, Key::Time(_))));
+        // the null bar is filled with the one before it
+        let values: Vec<Option<f64>> = panel.rows.values().map(|r| r[0]).collect();
+        assert_eq!(values[2], values[1]);
+
+        let datasets = vec![
+            load("assets/AAPL_5m_20210125.json"),
+            load("assets/AAPL_init_20210126.json"),
+        ];
+        assert!(Panel::new(&datasets, Field::Close, Fill::Blank).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+use crate::http::Result;
+use crate::resample::{Interval, Local};
+use crate::v8chart::{DataSet, Record};
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Months, TimeZone, Timelike};
+use csv::Writer;
+use serde::Serialize;
+use std::{future::Future, path::Path};
+
+/// Listings quoted in a minor currency unit, prone to being mixed up with the major unit
+const MINOR_UNITS: [&str; 4] = ["GBp", "GBX", "ZAc", "ILA"];
+
+/// A change made to a series, written to a sidecar log so that it can be audited
+#[derive(Serialize, Debug)]
+pub struct Repair {
+    pub symbol: String,
+    pub timestamp: DateTime<FixedOffset>,
+    pub kind: &'static str,
+    /// the field that changed, empty when the whole bar is affected
+    pub field: &'static str,
+    pub before: Option<f64>,
+    pub after: Option<f64>,
+}
+
+fn is_empty(r: &Record) -> bool {
+    [r.open, r.high, r.low, r.close].iter().all(Option::is_none) && r.volume.is_none()
+}
+
+/// the interval to refetch a bar of `granularity` with, and the interval to aggregate it back to
+fn finer(granularity: &str) -> Option<(&'static str, Interval)> {
+    match granularity {
+        "2m" => Some(("1m", Interval::Minutes(2))),
+        "5m" => Some(("1m", Interval::Minutes(5))),
+        "15m" => Some(("5m", Interval::Minutes(15))),
+        "30m" => Some(("5m", Interval::Minutes(30))),
+        "60m" | "1h" => Some(("5m", Interval::Minutes(60))),
+        "1d" => Some(("1h", Interval::Days(1))),
+        "1wk" => Some(("1d", Interval::Weeks(1))),
+        "1mo" => Some(("1d", Interval::Months(1))),
+        _ => None,
+    }
+}
+
+/// How far back in seconds yahoo serves a finer interval, which is also the longest span of one
+/// request for it. `None` when it goes back to the first trade
+fn window(interval: &str) -> Option<i64> {
+    let days = match interval {
+        "1m" => 7,
+        "5m" => 60,
+        "1h" => 730,
+        _ => return None,
+    };
+    Some(days * 86400)
+}
+
+/// median of up to three non-null closes on each side of bar `i`, robust to a neighbour that is
+/// off itself
+fn reference(closes: &[Option<f64>], i: usize) -> Option<f64> {
+    let mut window: Vec<f64> = closes[..i].iter().rev().flatten().take(3).copied().collect();
+    window.extend(closes.get(i + 1..).unwrap_or(&[]).iter().flatten().take(3));
+    window.retain(|c| *c > 0.0);
+    if window.is_empty() {
+        return None;
+    }
+    window.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = window.len() / 2;
+    if window.len().is_multiple_of(2) {
+        Some((window[mid - 1] + window[mid]) / 2.0)
+    } else {
+        Some(window[mid])
+    }
+}
+
+/// 0.01 or 100 if `p` is about 100 times off the reference price
+fn unit_correction(p: f64, reference: f64) -> Option<f64> {
+    let ratio = p / reference;
+    if (50.0..200.0).contains(&ratio) {
+        Some(0.01)
+    } else if (0.005..0.02).contains(&ratio) {
+        Some(100.0)
+    } else {
+        None
+    }
+}
+
+impl DataSet {
+    fn repair(
+        &self,
+        r: &Record,
+        kind: &'static str,
+        field: &'static str,
+        before: Option<f64>,
+        after: Option<f64>,
+    ) -> Repair {
+        Repair {
+            symbol: self.meta.symbol.clone(),
+            timestamp: r.timestamp,
+            kind,
+            field,
+            before,
+            after,
+        }
+    }
+
+    /// remove bars without any value
+    pub fn drop_empty_bars(&mut self) -> Vec<Repair> {
+        let repairs = self
+            .records
+            .iter()
+            .filter(|r| is_empty(r))
+            .map(|r| self.repair(r, "drop_empty_bar", "", None, None))
+            .collect();
+        self.records.retain(|r| !is_empty(r));
+        repairs
+    }
+
+    /// Undo a split adjustment that was applied twice. That is when the close before a split
+    /// bar is closer to its open divided by the split factor than to the open itself, in which
+    /// case every earlier bar is multiplied by the factor once more
+    pub fn repair_double_splits(&mut self) -> Vec<Repair> {
+        let mut repairs = Vec::new();
+        for i in 1..self.records.len() {
+            let factor = match self.records[i].split_factor {
+                Some(f) if f.ln().abs() > 0.1 => f,
+                _ => continue,
+            };
+            let open = match self.records[i].open.or(self.records[i].close) {
+                Some(p) if p > 0.0 => p,
+                _ => continue,
+            };
+            let prev = match self.records[..i].iter().rev().find_map(|r| r.close) {
+                Some(p) if p > 0.0 => p,
+                _ => continue,
+            };
+            if (prev * factor / open).ln().abs() < (prev / open).ln().abs() {
+                repairs.push(self.repair(
+                    &self.records[i],
+                    "double_split_adjustment",
+                    "close",
+                    Some(prev),
+                    Some(prev * factor),
+                ));
+                for r in self.records[..i].iter_mut() {
+                    r.scale(factor);
+                    r.adjclose = r.adjclose.map(|a| a * factor);
+                }
+            }
+        }
+        repairs
+    }
+
+    /// Fix prices that are 100 times off their neighbours on listings quoted in a minor unit
+    /// such as GBp or ZAc
+    pub fn repair_unit_errors(&mut self) -> Vec<Repair> {
+        if !self.meta.currency.as_deref().is_some_and(|c| MINOR_UNITS.contains(&c)) {
+            return Vec::new();
+        }
+        let closes: Vec<Option<f64>> = self.records.iter().map(|r| r.close).collect();
+        let mut repairs = Vec::new();
+        for i in 0..self.records.len() {
+            let reference = match reference(&closes, i) {
+                Some(reference) => reference,
+                None => continue,
+            };
+            let r = &self.records[i];
+            let mut fixes = Vec::new();
+            for (field, p) in [("open", r.open), ("high", r.high), ("low", r.low), ("close", r.close)].iter() {
+                if let Some(correction) = p.and_then(|p| unit_correction(p, reference)) {
+                    fixes.push((*field, correction));
+                    repairs.push(self.repair(r, "unit_error", field, *p, p.map(|p| p * correction)));
+                }
+            }
+            let r = &mut self.records[i];
+            for (field, correction) in fixes {
+                match field {
+                    "open" => r.open = r.open.map(|p| p * correction),
+                    "high" => r.high = r.high.map(|p| p * correction),
+                    "low" => r.low = r.low.map(|p| p * correction),
+                    _ => {
+                        r.close = r.close.map(|p| p * correction);
+                        r.adjclose = r.adjclose.map(|p| p * correction);
+                    }
+                }
+            }
+        }
+        repairs
+    }
+}
+
+/// The bars a series is expected to have: one every interval, on weekdays unless it is crypto and
+/// within the regular session for intraday bars. Exchange holidays are not known, a refetch of one
+/// comes back empty and nothing is added
+struct Calendar {
+    local: Local,
+    offset: FixedOffset,
+    interval: Interval,
+    /// the regular session in seconds from local midnight
+    session: Option<(u32, u32)>,
+    /// crypto trades on weekends too
+    weekends: bool,
+}
+
+impl Calendar {
+    fn new(ds: &DataSet, interval: Interval) -> Calendar {
+        let local = Local::new(ds);
+        let session = ds.meta.current_trading_period.as_ref().and_then(|p| {
+            let secs = |t: u64| {
+                DateTime::from_timestamp(t as i64, 0)
+                    .map(|t| local.to_local(&t.naive_utc()).num_seconds_from_midnight())
+            };
+            match (secs(p.regular.start), secs(p.regular.end)) {
+                (Some(start), Some(end)) if start < end => Some((start, end)),
+                _ => None,
+            }
+        });
+        Calendar {
+            local,
+            offset: ds.meta.offset(),
+            interval,
+            session,
+            weekends: ds.meta.instrument_type.as_deref() == Some("CRYPTOCURRENCY"),
+        }
+    }
+
+    /// the start of the bar after the one at `t`, days and longer are counted in local time
+    fn next(&self, t: &DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let local = self.local.to_local(&t.naive_utc());
+        let next = match self.interval {
+            Interval::Minutes(n) => return *t + Duration::minutes(n as i64),
+            Interval::Days(n) => local + Duration::days(n as i64),
+            Interval::Weeks(n) => local + Duration::weeks(n as i64),
+            Interval::Months(n) => local
+                .checked_add_months(Months::new(n))
+                .unwrap_or(local + Duration::days(31 * n as i64)),
+        };
+        self.offset.from_utc_datetime(&self.local.to_utc(&next))
+    }
+
+    fn is_trading(&self, t: &DateTime<FixedOffset>) -> bool {
+        let local = self.local.to_local(&t.naive_utc());
+        let weekend = !self.weekends && local.weekday().number_from_monday() > 5;
+        match self.interval {
+            Interval::Minutes(_) if !weekend => self
+                .session
+                .is_none_or(|(start, end)| (start..end).contains(&local.num_seconds_from_midnight())),
+            Interval::Minutes(_) | Interval::Days(_) => !weekend,
+            _ => true,
+        }
+    }
+}
+
+/// The missing bars of a run, each with its index when it is there but empty, and the start of
+/// the next bar with values
+type Run = (Vec<(DateTime<FixedOffset>, Option<usize>)>, DateTime<FixedOffset>);
+
+/// Refetch the bars missing from the middle of a series at a finer interval and aggregate them
+/// back. A bar is missing when it is empty or when the [Calendar] expects one where there is
+/// none. Runs of missing bars that start before yahoo's lookback of the finer interval as of `now`
+/// are skipped and the rest are fetched together as long as they fit in one request, `fetch`
+/// takes the start and end in epoch seconds and the finer interval. The adjclose of a refetched bar is carried over from a
+/// neighbour with the local adjustment factors, see [DataSet::adjustment_factors]
+pub async fn refetch_missing_bars<F, Fut>(ds: &mut DataSet, now: i64, mut fetch: F) -> Vec<Repair>
+where
+    F: FnMut(i64, i64, &'static str) -> Fut,
+    Fut: Future<Output = Result<Vec<DataSet>>>,
+{
+    let (interval, to) = match finer(&ds.meta.data_granularity) {
+        Some(finer) => finer,
+        None => return Vec::new(),
+    };
+    let mut repairs = Vec::new();
+    let first = ds.records.iter().position(|r| !is_empty(r));
+    let last = ds.records.iter().rposition(|r| !is_empty(r));
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return repairs,
+    };
+
+    let calendar = Calendar::new(ds, to);
+    let mut runs: Vec<Run> = Vec::new();
+    let mut run = Vec::new();
+    for i in first..last {
+        let r = &ds.records[i];
+        if is_empty(r) {
+            run.push((r.timestamp, Some(i)));
+        }
+        let end = ds.records[i + 1].timestamp;
+        let mut t = calendar.next(&r.timestamp);
+        while calendar.next(&t) <= end {
+            if calendar.is_trading(&t) {
+                run.push((t, None));
+            }
+            t = calendar.next(&t);
+        }
+        if !run.is_empty() && !is_empty(&ds.records[i + 1]) {
+            runs.push((std::mem::take(&mut run), end));
+        }
+    }
+
+    // holidays alone make a run each, so runs are merged into as few requests as possible
+    let window = window(interval);
+    let mut merged: Vec<Run> = Vec::new();
+    for (run, end) in runs {
+        let start = run[0].0.timestamp();
+        if window.is_some_and(|window| start < now - window) {
+            debug!(
+                "skipping the bars of {} from {} as yahoo does not serve {} that far back",
+                ds.meta.symbol, run[0].0, interval
+            );
+            continue;
+        }
+        match merged.last_mut() {
+            Some((slots, last_end))
+                if window.is_none_or(|window| end.timestamp() - slots[0].0.timestamp() <= window) =>
+            {
+                slots.extend(run);
+                *last_end = end;
+            }
+            _ => merged.push((run, end)),
+        }
+    }
+
+    let mut refetched = Vec::new();
+    let mut replaced = Vec::new();
+    for (run, end) in merged {
+        let start = run[0].0;
+        let fetched = match fetch(start.timestamp(), end.timestamp(), interval).await {
+            Ok(ds_vec) => ds_vec.into_iter().next(),
+            Err(err) => {
+                warn!("failed to refetch {} at {} with {:?}", ds.meta.symbol, start, err);
+                continue;
+            }
+        };
+        let bars = match fetched.map_or(Ok(Vec::new()), |finer| finer.resample(to).map(|ds| ds.records)) {
+            Ok(bars) => bars,
+            Err(err) => {
+                warn!("failed to refetch {} at {} with {}", ds.meta.symbol, start, err);
+                continue;
+            }
+        };
+        for bar in bars.into_iter().filter(|r| !is_empty(r)) {
+            let slot = run
+                .iter()
+                .find(|(t, _)| *t <= bar.timestamp && bar.timestamp < calendar.next(t));
+            if let Some((timestamp, i)) = slot {
+                let bar = Record {
+                    timestamp: *timestamp,
+                    adjclose: None,
+                    ..bar
+                };
+                repairs.push(ds.repair(&bar, "refetch_missing_bar", "close", None, bar.close));
+                refetched.push(bar);
+                replaced.extend(i);
+            }
+        }
+    }
+    if refetched.is_empty() {
+        return repairs;
+    }
+
+    let timestamps: Vec<DateTime<FixedOffset>> = refetched.iter().map(|r| r.timestamp).collect();
+    replaced.sort_unstable();
+    for i in replaced.into_iter().rev() {
+        ds.records.remove(i);
+    }
+    ds.records.append(&mut refetched);
+    ds.records.sort_by_key(|r| r.timestamp);
+    ds.attach_events();
+    ds.accumulate_splits();
+    fill_adjclose(ds, &timestamps);
+    repairs
+}
+
+/// Fill the adjclose of the bars at `timestamps` from the nearest later bar with one, or the
+/// nearest earlier one, scaled by the ratio of their local adjustment factors
+fn fill_adjclose(ds: &mut DataSet, timestamps: &[DateTime<FixedOffset>]) {
+    let factors = ds.adjustment_factors(false);
+    let ratio = |j: usize| {
+        let r = &ds.records[j];
+        match (r.adjclose, r.close) {
+            (Some(adjclose), Some(close)) if close > 0.0 && !timestamps.contains(&r.timestamp) => {
+                Some(adjclose / close / factors[j])
+            }
+            _ => None,
+        }
+    };
+    let adjcloses: Vec<Option<f64>> = (0..ds.records.len())
+        .map(|i| {
+            let r = &ds.records[i];
+            if !timestamps.contains(&r.timestamp) {
+                return r.adjclose;
+            }
+            let ratio = (i + 1..ds.records.len())
+                .find_map(ratio)
+                .or_else(|| (0..i).rev().find_map(ratio))?;
+            r.close.map(|close| close * factors[i] * ratio)
+        })
+        .collect();
+    for (r, adjclose) in ds.records.iter_mut().zip(adjcloses) {
+        r.adjclose = adjclose;
+    }
+}
+
+/// Run every repair in turn: refetch missing bars, drop the remaining empty ones, undo double
+/// split adjustments and fix unit errors
+pub async fn repair<F, Fut>(ds: &mut DataSet, now: i64, fetch: F) -> Vec<Repair>
+where
+    F: FnMut(i64, i64, &'static str) -> Fut,
+    Fut: Future<Output = Result<Vec<DataSet>>>,
+{
+    let mut repairs = refetch_missing_bars(ds, now, fetch).await;
+    repairs.append(&mut ds.drop_empty_bars());
+    repairs.append(&mut ds.repair_double_splits());
+    repairs.append(&mut ds.repair_unit_errors());
+    repairs
+}
+
+/// write the repair log as csv
+pub fn write_repairs<P: AsRef<Path>>(
+    repairs: &[Repair],
+    path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = Writer::from_path(path)?;
+    for r in repairs.iter() {
+        wtr.serialize(r)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    /// 2021-01-27, shortly after the fixtures were downloaded
+    const NOW: i64 = 1611705600;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_unit_errors() {
+        let mut ds = load("assets/VOD.L_20210104_20210108.json");
+        let repairs = ds.repair_unit_errors();
+        let fields: Vec<&str> = repairs.iter().map(|r| r.field).collect();
+        assert_eq!(fields, vec!["open", "high", "low", "close", "close"]);
+        assert_eq!(ds.records[1].close, Some(136.5));
+        assert_eq!(ds.records[1].adjclose, Some(136.5));
+        assert_eq!(ds.records[3].close, Some(139.0));
+        assert_eq!(ds.records[3].open, Some(138.0));
+
+        let mut ds = load("assets/^GSPC_20210104_20210108.json");
+        ds.records[1].close = ds.records[1].close.map(|c| c * 100.0);
+        assert!(ds.repair_unit_errors().is_empty());
+    }
+
+    #[test]
+    fn test_double_splits() {
+        let mut ds = load("assets/AAPL_init_20210126.json");
+        assert!(ds.repair_double_splits().is_empty());
+        let split = ds
+            .records
+            .iter()
+            .position(|r| r.split.as_deref() == Some("4:1"))
+            .unwrap();
+        let before = ds.records[split - 1].close.unwrap();
+        for r in ds.records[..split].iter_mut() {
+            r.scale(0.25);
+        }
+        let repairs = ds.repair_double_splits();
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].kind, "double_split_adjustment");
+        assert!((ds.records[split - 1].close.unwrap() - before).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_refetch_and_drop() {
+        let expected = load("assets/^GSPC_20210104_20210108.json");
+        let mut ds = load("assets/^GSPC_20210104_20210108.json");
+        for r in ds.records.iter_mut() {
+            r.adjclose = r.close.map(|c| c * 0.5);
+        }
+        ds.records.remove(1);
+        let calls = std::cell::Cell::new(0);
+        let repairs = repair(&mut ds, NOW, |start, end, interval| {
+            calls.set(calls.get() + 1);
+            assert_eq!(interval, "1h");
+            assert_eq!((start, end), (1609857000, 1609943400));
+            async { Ok(vec![load("assets/^GSPC_1h_20210105_20210106.json")]) }
+        })
+        .await;
+        assert_eq!(calls.get(), 1);
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].kind, "refetch_missing_bar");
+        assert_eq!(ds.records.len(), 4);
+        let (bar, want) = (&ds.records[1], &expected.records[1]);
+        assert_eq!(bar.timestamp, want.timestamp);
+        assert_eq!(
+            (bar.open, bar.high, bar.low, bar.close),
+            (want.open, want.high, want.low, want.close)
+        );
+        assert_eq!(bar.volume, want.volume);
+        assert_eq!(bar.adjclose, want.close.map(|c| c * 0.5));
+
+        // an empty bar is refetched the same way and dropped when that fails
+        let r = &mut ds.records[2];
+        r.open = None;
+        r.high = None;
+        r.low = None;
+        r.close = None;
+        r.volume = None;
+        let repairs = repair(&mut ds, NOW, |start, _, _| {
+            assert_eq!(start, 1609943400);
+            async { Err("offline".into()) }
+        })
+        .await;
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].kind, "drop_empty_bar");
+        assert_eq!(ds.records.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_find_missing_bars() {
+        // nothing is missing over a weekend
+        let mut ds = load("assets/^GSPC_20210106_20210111.json");
+        let repairs = refetch_missing_bars(&mut ds, NOW, |_, _, _| async { panic!("nothing to refetch") }).await;
+        assert!(repairs.is_empty());
+
+        // a missing 5m bar and the empty one after it are fetched at once, up to the next bar
+        let mut ds = load("assets/AAPL_5m_20210125.json");
+        ds.records.remove(1);
+        let calls = std::cell::Cell::new(0);
+        refetch_missing_bars(&mut ds, NOW, |start, end, interval| {
+            calls.set(calls.get() + 1);
+            assert_eq!((start, end, interval), (1611585300, 1611585900, "1m"));
+            async { Err("offline".into()) }
+        })
+        .await;
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_merge_and_skip_runs() {
+        // the runs of two missing days are fetched in one request
+        let mut ds = load("assets/^GSPC_20210104_20210108.json");
+        ds.records.remove(1);
+        ds.records
+            .push(load("assets/^GSPC_20210106_20210111.json").records.remove(3));
+        let calls = std::cell::Cell::new(0);
+        let repairs = refetch_missing_bars(&mut ds, NOW, |start, end, _| {
+            calls.set(calls.get() + 1);
+            assert_eq!((start, end), (1609857000, 1610375400));
+            async { Ok(vec![load("assets/^GSPC_1h_20210105_20210106.json")]) }
+        })
+        .await;
+        assert_eq!(calls.get(), 1);
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(ds.records.len(), 5);
+
+        // yahoo only serves 1h bars for about two years
+        let mut ds = load("assets/^GSPC_20210104_20210108.json");
+        ds.records.remove(1);
+        let now = NOW + 800 * 86400;
+        let repairs = refetch_missing_bars(&mut ds, now, |_, _, _| async { panic!("out of the lookback") }).await;
+        assert!(repairs.is_empty());
+    }
+}
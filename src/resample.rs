@@ -0,0 +1,308 @@
+use crate::v8chart::{DataSet, Record};
+
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use chrono_tz::Tz;
+use std::{fmt, str::FromStr};
+
+/// A bar interval, including the ones yahoo does not offer such as `4h` or `2d`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interval {
+    Minutes(u32),
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (n, unit) = s.split_at(split);
+        let n: u32 = n.parse().map_err(|_| format!("missing the count of interval {}", s))?;
+        if n == 0 {
+            return Err(format!("interval {} must be positive", s));
+        }
+        match unit {
+            "m" => Ok(Interval::Minutes(n)),
+            "h" => Ok(Interval::Minutes(n * 60)),
+            "d" => Ok(Interval::Days(n)),
+            "wk" => Ok(Interval::Weeks(n)),
+            "mo" => Ok(Interval::Months(n)),
+            _ => Err(format!("unknown unit of interval {}", s)),
+        }
+    }
+}
+
+impl Interval {
+    /// the nominal length to tell a coarser interval from a finer one, a month counts as 30 days
+    fn seconds(self) -> i64 {
+        match self {
+            Interval::Minutes(n) => n as i64 * 60,
+            Interval::Days(n) => n as i64 * 86400,
+            Interval::Weeks(n) => n as i64 * 7 * 86400,
+            Interval::Months(n) => n as i64 * 30 * 86400,
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Interval::Minutes(n) if n % 60 == 0 => write!(f, "{}h", n / 60),
+            Interval::Minutes(n) => write!(f, "{}m", n),
+            Interval::Days(n) => write!(f, "{}d", n),
+            Interval::Weeks(n) => write!(f, "{}wk", n),
+            Interval::Months(n) => write!(f, "{}mo", n),
+        }
+    }
+}
+
+/// Converts timestamps to exchange-local time, DST aware when the exchange timezone is known
+pub(crate) enum Local {
+    Zone(Tz),
+    Fixed(FixedOffset),
+}
+
+impl Local {
+    pub(crate) fn new(ds: &DataSet) -> Self {
+        match ds.meta.exchange_timezone_name.as_ref().map(|name| name.parse::<Tz>()) {
+            Some(Ok(tz)) => Local::Zone(tz),
+            _ => Local::Fixed(ds.meta.offset()),
+        }
+    }
+
+    pub(crate) fn to_local(&self, utc: &NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Local::Zone(tz) => tz.from_utc_datetime(utc).naive_local(),
+            Local::Fixed(offset) => offset.from_utc_datetime(utc).naive_local(),
+        }
+    }
+
+    pub(crate) fn to_utc(&self, local: &NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Local::Zone(tz) => tz
+                .from_local_datetime(local)
+                .earliest()
+                .map_or(*local, |t| t.naive_utc()),
+            Local::Fixed(offset) => *local - chrono::Duration::seconds(offset.local_minus_utc() as i64),
+        }
+    }
+}
+
+/// key of the bucket a local time falls into, buckets of the same key are merged. Buckets of `n`
+/// days count from 0001-01-01 rather than the first bar, so `2d` always pairs the same calendar
+/// days and a weekend can leave a trading day alone in its bucket
+fn bucket(to: Interval, local: &NaiveDateTime, session_start: u32) -> (i64, i64) {
+    let date = local.date();
+    match to {
+        Interval::Minutes(n) => {
+            let secs = local.num_seconds_from_midnight() as i64 - session_start as i64;
+            (date.num_days_from_ce() as i64, secs.div_euclid(n as i64 * 60))
+        }
+        Interval::Days(n) => ((date.num_days_from_ce() as i64).div_euclid(n as i64), 0),
+        Interval::Weeks(n) => {
+            let monday = date.num_days_from_ce() as i64 - date.weekday().num_days_from_monday() as i64;
+            (monday.div_euclid(7 * n as i64), 0)
+        }
+        Interval::Months(n) => ((date.year() as i64 * 12 + date.month0() as i64).div_euclid(n as i64), 0),
+    }
+}
+
+/// merge the bars of one bucket: first open, max high, min low, last close and summed volume
+fn aggregate(bars: &[Record], timestamp: DateTime<FixedOffset>) -> Record {
+    let first = |f: fn(&Record) -> Option<f64>| bars.iter().find_map(f);
+    let last = |f: fn(&Record) -> Option<f64>| bars.iter().rev().find_map(f);
+    let sum = |f: fn(&Record) -> Option<f64>| {
+        bars.iter()
+            .filter_map(f)
+            .fold(None, |acc, x| Some(acc.unwrap_or(0.0) + x))
+    };
+    let splits: Vec<&str> = bars.iter().filter_map(|r| r.split.as_deref()).collect();
+    Record {
+        timestamp,
+        volume: bars
+            .iter()
+            .filter_map(|r| r.volume)
+            .fold(None, |acc, v| Some(acc.unwrap_or(0) + v)),
+        high: bars
+            .iter()
+            .filter_map(|r| r.high)
+            .fold(None, |acc, x| Some(acc.map_or(x, |a: f64| a.max(x)))),
+        low: bars
+            .iter()
+            .filter_map(|r| r.low)
+            .fold(None, |acc, x| Some(acc.map_or(x, |a: f64| a.min(x)))),
+        open: first(|r| r.open),
+        close: last(|r| r.close),
+        adjclose: last(|r| r.adjclose),
+        split: if splits.is_empty() {
+            None
+        } else {
+            Some(splits.join(","))
+        },
+        split_factor: bars
+            .iter()
+            .filter_map(|r| r.split_factor)
+            .fold(None, |acc, x| Some(acc.unwrap_or(1.0) * x)),
+        dividend: sum(|r| r.dividend),
+        capital_gain: sum(|r| r.capital_gain),
+        cum_split_factor: 1.0,
+    }
+}
+
+impl DataSet {
+    /// Aggregate bars into a coarser interval. Intraday buckets are aligned to the start of the
+    /// regular session in exchange-local time, daily and longer ones to local calendar days (see
+    /// [bucket] for multi-day ones), weeks starting on Monday and months. Intraday bars are stamped
+    /// with the bucket start and longer ones with their first bar. Dividends and capital gains are
+    /// summed and splits multiplied. A target that is not coarser than the bars is an error.
+    pub fn resample(self, to: Interval) -> Result<DataSet, String> {
+        if let Ok(from) = self.meta.data_granularity.parse::<Interval>() {
+            if to.seconds() <= from.seconds() {
+                return Err(format!(
+                    "cannot resample {} {} into {} as it is not coarser",
+                    self.meta.symbol, self.meta.data_granularity, to
+                ));
+            }
+        }
+        let local = Local::new(&self);
+        let session_start = self
+            .meta
+            .current_trading_period
+            .as_ref()
+            .and_then(|p| DateTime::from_timestamp(p.regular.start as i64, 0))
+            .map(|start| local.to_local(&start.naive_utc()).num_seconds_from_midnight())
+            .unwrap_or(0);
+        let offset = self.meta.offset();
+
+        let mut records: Vec<Record> = Vec::new();
+        let mut bars: Vec<Record> = Vec::new();
+        let mut current = None;
+        let mut flush = |bars: &mut Vec<Record>, key: (i64, i64)| {
+            if bars.is_empty() {
+                return;
+            }
+            let timestamp = match to {
+                Interval::Minutes(n) => {
+                    let date = NaiveDate::from_num_days_from_ce_opt(key.0 as i32).unwrap_or_default();
+                    let start = date.and_hms_opt(0, 0, 0).unwrap()
+                        + chrono::Duration::seconds(session_start as i64 + key.1 * n as i64 * 60);
+                    offset.from_utc_datetime(&local.to_utc(&start))
+                }
+                _ => bars[0].timestamp,
+            };
+            records.push(aggregate(bars, timestamp));
+            bars.clear();
+        };
+        for r in self.records.into_iter() {
+            let key = bucket(to, &local.to_local(&r.timestamp.naive_utc()), session_start);
+            if let Some(prev) = current {
+                if prev != key {
+                    flush(&mut bars, prev);
+                }
+            }
+            current = Some(key);
+            bars.push(r);
+        }
+        if let Some(key) = current {
+            flush(&mut bars, key);
+        }
+
+        let mut meta = self.meta;
+        meta.data_granularity = to.to_string();
+        let mut ds = DataSet {
+            records,
+            events: self.events,
+            meta,
+        };
+        ds.accumulate_splits();
+        Ok(ds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!("15m".parse::<Interval>(), Ok(Interval::Minutes(15)));
+        assert_eq!("4h".parse::<Interval>(), Ok(Interval::Minutes(240)));
+        assert_eq!("2d".parse::<Interval>(), Ok(Interval::Days(2)));
+        assert_eq!("1wk".parse::<Interval>(), Ok(Interval::Weeks(1)));
+        assert_eq!("3mo".parse::<Interval>(), Ok(Interval::Months(3)));
+        assert!("h".parse::<Interval>().is_err());
+        assert!("0d".parse::<Interval>().is_err());
+        assert!("1y".parse::<Interval>().is_err());
+        assert_eq!(Interval::Minutes(240).to_string(), "4h");
+    }
+
+    #[test]
+    fn test_resample_intraday() {
+        let ds = load("assets/AAPL_5m_20210125.json")
+            .resample("15m".parse().unwrap())
+            .unwrap();
+        assert_eq!(ds.meta.data_granularity, "15m");
+        assert_eq!(ds.records.len(), 2);
+        let bar = &ds.records[0];
+        assert_eq!(bar.open, Some(143.07000732421875));
+        assert_eq!(bar.high, Some(143.1699981689453));
+        assert_eq!(bar.low, Some(141.57000732421875));
+        assert_eq!(bar.close, Some(141.6199951171875));
+        assert_eq!(bar.volume, Some(6412043 + 3155512));
+        assert_eq!(ds.records[1].timestamp.timestamp(), 1611585900);
+    }
+
+    #[test]
+    fn test_resample_session_dst() {
+        // the meta is in EST while the bars are in EDT, buckets should still start at 9:30 local
+        let ds = load("assets/AAPL_30m_20200706_20200707.json")
+            .resample("1h".parse().unwrap())
+            .unwrap();
+        assert_eq!(ds.records.len(), 14);
+        assert_eq!(ds.records[0].timestamp.timestamp(), 1594042200);
+        assert_eq!(ds.records[1].timestamp.timestamp(), 1594042200 + 3600);
+        assert_eq!(ds.records[7].timestamp.timestamp(), 1594128600);
+        let volume: u64 = ds.records.iter().filter_map(|r| r.volume).sum();
+        let raw = load("assets/AAPL_30m_20200706_20200707.json");
+        assert_eq!(volume, raw.records.iter().filter_map(|r| r.volume).sum::<u64>());
+        let daily = raw.resample("1d".parse().unwrap()).unwrap();
+        assert_eq!(daily.records.len(), 2);
+    }
+
+    #[test]
+    fn test_resample_coarser() {
+        let ds = load("assets/AAPL_5m_20210125.json");
+        assert!(ds.resample("5m".parse().unwrap()).is_err());
+        let ds = load("assets/^GSPC_20210104_20210108.json");
+        assert!(ds.resample("15m".parse().unwrap()).is_err());
+        // days are paired from CE rather than from the first bar, Friday and Monday are left alone
+        // as they share their buckets with the weekend
+        let ds = load("assets/^GSPC_20210106_20210111.json").resample("2d".parse().unwrap());
+        let days: Vec<u32> = ds.unwrap().records.iter().map(|r| r.timestamp.day()).collect();
+        assert_eq!(days, vec![6, 8, 11]);
+    }
+
+    #[test]
+    fn test_resample_weekly_events() {
+        let raw = load("assets/AAPL_init_20210126.json");
+        let dividends: f64 = raw.records.iter().filter_map(|r| r.dividend).sum();
+        let first_factor = raw.records[0].cum_split_factor;
+        let ds = raw.resample("1wk".parse().unwrap()).unwrap();
+        assert_eq!(ds.records.iter().filter(|r| r.split.is_some()).count(), 5);
+        let resampled: f64 = ds.records.iter().filter_map(|r| r.dividend).sum();
+        assert!((dividends - resampled).abs() < 1e-9);
+        assert_eq!(ds.records[0].cum_split_factor, first_factor);
+        for w in ds.records.windows(2) {
+            assert!(w[0].timestamp < w[1].timestamp);
+            assert!(w[1].timestamp - w[0].timestamp >= chrono::Duration::days(3));
+        }
+    }
+}
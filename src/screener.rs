@@ -0,0 +1,209 @@
+use crate::http::{get_bytes, make_client, post_json, HttpsClient, Result};
+use crate::options::ScreenOpts as Opts;
+
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs::read_to_string, path::Path};
+use tokio::time::sleep;
+
+/// A filter node of a custom screener, e.g. `{"operator": "GT", "operands": ["percentchange", 3]}`
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Filter {
+    pub operator: String,
+    pub operands: Vec<Operand>,
+}
+
+/// An operand is either a nested filter, a field name or a value
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Operand {
+    Filter(Filter),
+    Number(f64),
+    Text(String),
+}
+
+/// A custom screener query as posted to yahoo
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Query {
+    #[serde(default = "default_quote_type")]
+    pub quote_type: String,
+    #[serde(default = "default_sort_field")]
+    pub sort_field: String,
+    #[serde(default = "default_sort_type")]
+    pub sort_type: String,
+    pub query: Filter,
+}
+
+fn default_quote_type() -> String {
+    "EQUITY".to_string()
+}
+
+fn default_sort_field() -> String {
+    "ticker".to_string()
+}
+
+fn default_sort_type() -> String {
+    "ASC".to_string()
+}
+
+/// The paged request body of a custom screener
+#[derive(Serialize, Debug)]
+struct PagedQuery<'a> {
+    offset: u32,
+    size: u32,
+    #[serde(flatten)]
+    query: &'a Query,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScreenerQuote {
+    pub symbol: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScreenerResult {
+    #[serde(default)]
+    pub total: u32,
+    #[serde(default)]
+    pub quotes: Vec<ScreenerQuote>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Finance {
+    pub result: Option<Vec<ScreenerResult>>,
+    pub error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScreenerResponse {
+    pub finance: Finance,
+}
+
+/// read a custom query from a JSON or TOML file, decided by the extension
+pub fn load_query<P: AsRef<Path>>(path: P) -> Result<Query> {
+    let path = path.as_ref();
+    let content = read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => Ok(toml::from_str(&content)?),
+        _ => Ok(serde_json::from_str(&content)?),
+    }
+}
+
+/// parse one page of screener results into the total number of matches and the symbols
+pub fn parse_page(body: &[u8]) -> Result<(u32, Vec<String>)> {
+    let resp: ScreenerResponse = serde_json::from_slice(body)?;
+    if let Some(err) = resp.finance.error {
+        return Err(format!("screener error: {}", err).into());
+    }
+    let result = resp
+        .finance
+        .result
+        .and_then(|r| r.into_iter().next())
+        .ok_or("screener returned no result")?;
+    Ok((result.total, result.quotes.into_iter().map(|q| q.symbol).collect()))
+}
+
+/// compose a predefined screener request URI
+fn make_uri(base: &str, id: &str, start: u32, count: u32) -> hyper::Uri {
+    let url = url::Url::parse_with_params(
+        &format!("{}/predefined/saved", base),
+        &[
+            ("scrIds", id.to_owned()),
+            ("start", start.to_string()),
+            ("count", count.to_string()),
+        ],
+    )
+    .unwrap();
+    debug!("{}", url.as_str());
+    url.into_string().parse().unwrap()
+}
+
+/// page through a predefined screener
+async fn run_predefined(client: &HttpsClient, opts: &Opts, id: &str) -> Result<Vec<String>> {
+    let mut symbols = Vec::new();
+    loop {
+        let uri = make_uri(&opts.base_url, id, symbols.len() as u32, opts.page_size);
+        let (total, mut page) = parse_page(&get_bytes(client, uri, id).await?)?;
+        let done = page.is_empty();
+        symbols.append(&mut page);
+        if done || symbols.len() as u32 >= total {
+            return Ok(symbols);
+        }
+        sleep(opts.rate.0).await;
+    }
+}
+
+/// page through a custom screener query
+async fn run_query(client: &HttpsClient, opts: &Opts, query: &Query) -> Result<Vec<String>> {
+    let mut symbols = Vec::new();
+    loop {
+        let body = serde_json::to_string(&PagedQuery {
+            offset: symbols.len() as u32,
+            size: opts.page_size,
+            query,
+        })?;
+        let uri = opts.base_url.parse()?;
+        let (total, mut page) = parse_page(&post_json(client, uri, body, "custom screener").await?)?;
+        let done = page.is_empty();
+        symbols.append(&mut page);
+        if done || symbols.len() as u32 >= total {
+            return Ok(symbols);
+        }
+        sleep(opts.rate.0).await;
+    }
+}
+
+/// Entry function that takes a [crate::options::ScreenOpts] and returns the unique symbols
+/// matched by all the screeners in the order they were found
+pub async fn screen(opts: &Opts) -> Result<Vec<String>> {
+    let client = make_client();
+    let mut symbols = Vec::new();
+    for id in opts.screeners.iter() {
+        match run_predefined(&client, opts, id).await {
+            Ok(mut found) => {
+                info!("screener {} matched {} symbols", id, found.len());
+                symbols.append(&mut found);
+            }
+            Err(err) => error!("failed to run screener {} with {:?}", id, err),
+        }
+    }
+    if let Some(path) = opts.query.as_ref() {
+        let query = load_query(path)?;
+        let mut found = run_query(&client, opts, &query).await?;
+        info!("custom screener {} matched {} symbols", path, found.len());
+        symbols.append(&mut found);
+    }
+    let mut seen = HashSet::new();
+    symbols.retain(|s| seen.insert(s.clone()));
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_page() {
+        let body = std::fs::read("assets/day_gainers_20210126.json").unwrap();
+        let (total, symbols) = parse_page(&body).unwrap();
+        assert_eq!(total, 112);
+        assert_eq!(symbols, vec!["GME", "AMC", "BB"]);
+    }
+
+    #[test]
+    fn test_load_query() {
+        let from_json = load_query("assets/screener_query.json").unwrap();
+        let from_toml = load_query("assets/screener_query.toml").unwrap();
+        assert_eq!(from_json, from_toml);
+        assert_eq!(from_json.sort_type, "DESC");
+        let body = serde_json::to_value(&PagedQuery {
+            offset: 100,
+            size: 50,
+            query: &from_json,
+        })
+        .unwrap();
+        assert_eq!(body["offset"], 100);
+        assert_eq!(body["quoteType"], "EQUITY");
+        assert_eq!(body["query"]["operands"][0]["operands"][1], 3.0);
+    }
+}
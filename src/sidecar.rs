@@ -0,0 +1,160 @@
+use crate::options::DownloadOpts;
+use crate::v8chart::{DataSet, V8Meta};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+/// The parameters a json was downloaded with
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Request {
+    pub symbol: String,
+    pub interval: String,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    pub include_pre_post: bool,
+}
+
+impl Request {
+    pub fn new(opts: &DownloadOpts, symbol: &str) -> Request {
+        Request {
+            symbol: symbol.to_string(),
+            interval: opts.interval.clone(),
+            start: opts.start,
+            end: opts.end,
+            include_pre_post: opts.include_pre_post,
+        }
+    }
+}
+
+/// What a dataset is, written to a `.meta.json` next to the json and to each of its converted
+/// outputs so that the currency, exchange and timezone of the numbers are not lost
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Sidecar {
+    pub meta: V8Meta,
+    /// unknown when the json was not downloaded by this tool
+    pub request: Option<Request>,
+    /// when the json was downloaded, or modified when that is unknown
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// the file name of the json
+    pub source: String,
+    /// how the file next to it is adjusted, see [crate::adjust::Adjustment]
+    pub adjust: String,
+    pub bars: usize,
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+}
+
+impl Sidecar {
+    pub fn new(ds: &DataSet, source: &Path) -> Sidecar {
+        let fetched_at = source
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from);
+        Sidecar {
+            meta: ds.meta.clone(),
+            request: None,
+            fetched_at,
+            source: source
+                .file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().to_string()),
+            adjust: "none".to_string(),
+            bars: ds.records.len(),
+            first: ds.records.first().map(|r| r.timestamp.with_timezone(&Utc)),
+            last: ds.records.last().map(|r| r.timestamp.with_timezone(&Utc)),
+        }
+    }
+
+    /// keep the request and fetch time of an earlier sidecar of the same dataset. The adjustment
+    /// is not carried over as it describes the file the sidecar is next to
+    pub fn inherit(&mut self, earlier: Sidecar) {
+        if self.request.is_none() {
+            self.request = earlier.request;
+        }
+        if earlier.fetched_at.is_some() {
+            self.fetched_at = earlier.fetched_at;
+        }
+    }
+}
+
+/// the sidecar of a single-dataset file, `{file name}.meta.json` next to it so that the json and
+/// every output converted from it have their own
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".meta.json");
+    path.with_file_name(name)
+}
+
+/// read a sidecar from file
+pub fn load_sidecar<P: AsRef<Path>>(path: P) -> Result<Sidecar, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// write a sidecar to path as pretty json
+pub fn write_sidecar<P: AsRef<Path>>(sidecar: &Sidecar, path: P) -> Result<(), Box<dyn Error>> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(writer, sidecar)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+    use rand::{distributions::Alphanumeric, thread_rng, Rng};
+    use std::fs::remove_file;
+
+    #[test]
+    fn test_sidecar() {
+        let source = Path::new("assets/AUDUSD=X_20210104_20210106.json");
+        let ds_vec: Vec<DataSet> = load_from_json(source.to_str().unwrap()).unwrap().chart.into();
+        let mut sidecar = Sidecar::new(&ds_vec[0], source);
+        assert_eq!(sidecar.source, "AUDUSD=X_20210104_20210106.json");
+        assert_eq!(sidecar.bars, ds_vec[0].records.len());
+        assert!(sidecar.fetched_at.is_some());
+
+        let fetched_at = "2021-01-06T12:00:00Z".parse().unwrap();
+        let request = Request {
+            symbol: "AUDUSD=X".to_string(),
+            interval: "1d".to_string(),
+            start: NaiveDate::from_ymd_opt(2021, 1, 4),
+            end: NaiveDate::from_ymd_opt(2021, 1, 6),
+            include_pre_post: false,
+        };
+        sidecar.inherit(Sidecar {
+            request: Some(request.clone()),
+            fetched_at: Some(fetched_at),
+            adjust: "all".to_string(),
+            ..sidecar.clone()
+        });
+        assert_eq!(sidecar.request.as_ref(), Some(&request));
+        assert_eq!(sidecar.fetched_at, Some(fetched_at));
+        // the adjustment describes the file the sidecar is next to
+        assert_eq!(sidecar.adjust, "none");
+        assert_eq!(
+            sidecar_path(Path::new("out/AAPL.csv")),
+            Path::new("out/AAPL.csv.meta.json")
+        );
+
+        let name: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+        let path = std::env::temp_dir().join(name + ".meta.json");
+        write_sidecar(&sidecar, &path).unwrap();
+        let loaded = load_sidecar(&path).unwrap();
+        assert_eq!(loaded.meta.currency.as_deref(), Some("USD"));
+        assert_eq!(loaded.meta.exchange_name, sidecar.meta.exchange_name);
+        assert_eq!(loaded.request, Some(request));
+        assert_eq!(loaded.first, sidecar.first);
+        let _ = remove_file(path);
+    }
+}
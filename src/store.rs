@@ -0,0 +1,229 @@
+use crate::v8chart::DataSet;
+
+use rusqlite::{params, Connection};
+use std::{error::Error, path::Path, path::PathBuf, str::FromStr};
+
+/// A store location such as `sqlite:path.db`
+#[derive(Debug, Clone)]
+pub struct StoreUrl(pub PathBuf);
+
+impl FromStr for StoreUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("sqlite:") {
+            Some(path) if !path.is_empty() => Ok(StoreUrl(PathBuf::from(path))),
+            _ => Err(format!("unsupported store {}, expecting sqlite:path.db", s)),
+        }
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS symbols (
+    symbol TEXT PRIMARY KEY,
+    currency TEXT,
+    exchange_name TEXT NOT NULL,
+    instrument_type TEXT,
+    first_trade_date INTEGER,
+    exchange_timezone_name TEXT,
+    gmtoffset INTEGER NOT NULL,
+    price_hint REAL,
+    regular_market_time INTEGER,
+    -- the whole V8Meta as JSON
+    meta TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS bars (
+    symbol TEXT NOT NULL REFERENCES symbols (symbol),
+    interval TEXT NOT NULL,
+    -- epoch seconds
+    timestamp INTEGER NOT NULL,
+    open REAL,
+    high REAL,
+    low REAL,
+    close REAL,
+    adjclose REAL,
+    volume INTEGER,
+    PRIMARY KEY (symbol, interval, timestamp)
+);
+CREATE TABLE IF NOT EXISTS events (
+    symbol TEXT NOT NULL REFERENCES symbols (symbol),
+    timestamp INTEGER NOT NULL,
+    -- dividend, capital_gain or split
+    kind TEXT NOT NULL,
+    -- the cash amount, or the number of new shares per old share of a split
+    amount REAL NOT NULL,
+    -- the split ratio as reported, e.g. 4:1
+    ratio TEXT,
+    PRIMARY KEY (symbol, timestamp, kind)
+);
+";
+
+/// A normalised SQLite database of symbols, bars and events. Every write is an upsert so that
+/// repeated and overlapping downloads never duplicate a row, the latest write wins.
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// open or create a database at `path` and make sure the tables exist
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Store> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Store { conn })
+    }
+
+    /// upsert the meta, bars and events of a dataset in one transaction, returns the number of bars
+    pub fn upsert(&mut self, ds: &DataSet) -> Result<usize, Box<dyn Error>> {
+        let meta = &ds.meta;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO symbols (symbol, currency, exchange_name, instrument_type, first_trade_date,
+                exchange_timezone_name, gmtoffset, price_hint, regular_market_time, meta)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            ON CONFLICT (symbol) DO UPDATE SET
+                currency = excluded.currency,
+                exchange_name = excluded.exchange_name,
+                instrument_type = excluded.instrument_type,
+                first_trade_date = excluded.first_trade_date,
+                exchange_timezone_name = excluded.exchange_timezone_name,
+                gmtoffset = excluded.gmtoffset,
+                price_hint = excluded.price_hint,
+                regular_market_time = excluded.regular_market_time,
+                meta = excluded.meta",
+            params![
+                meta.symbol,
+                meta.currency,
+                meta.exchange_name,
+                meta.instrument_type,
+                meta.first_trade_date,
+                meta.exchange_timezone_name,
+                meta.gmtoffset,
+                meta.price_hint,
+                meta.regular_market_time,
+                serde_json::to_string(meta)?,
+            ],
+        )?;
+        {
+            let mut bar = tx.prepare(
+                "INSERT INTO bars (symbol, interval, timestamp, open, high, low, close, adjclose, volume)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                ON CONFLICT (symbol, interval, timestamp) DO UPDATE SET
+                    open = excluded.open,
+                    high = excluded.high,
+                    low = excluded.low,
+                    close = excluded.close,
+                    adjclose = excluded.adjclose,
+                    volume = excluded.volume",
+            )?;
+            let mut event = tx.prepare(
+                "INSERT INTO events (symbol, timestamp, kind, amount, ratio) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (symbol, timestamp, kind) DO UPDATE SET
+                    amount = excluded.amount,
+                    ratio = excluded.ratio",
+            )?;
+            for r in ds.records.iter() {
+                bar.execute(params![
+                    meta.symbol,
+                    meta.data_granularity,
+                    r.timestamp.timestamp(),
+                    r.open,
+                    r.high,
+                    r.low,
+                    r.close,
+                    r.adjclose,
+                    r.volume.map(|v| v as i64),
+                ])?;
+            }
+            // events at their own time rather than the bar they are attached to, which depends on
+            // the interval
+            for e in ds.events.iter() {
+                event.execute(params![meta.symbol, e.timestamp.timestamp(), e.kind, e.amount, e.ratio])?;
+            }
+        }
+        tx.commit()?;
+        Ok(ds.records.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    fn count(store: &Store, table: &str) -> i64 {
+        store
+            .conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            "sqlite:data/bars.db".parse::<StoreUrl>().unwrap().0,
+            PathBuf::from("data/bars.db")
+        );
+        assert!("postgres://localhost".parse::<StoreUrl>().is_err());
+        assert!("sqlite:".parse::<StoreUrl>().is_err());
+    }
+
+    #[test]
+    fn test_upsert() {
+        let mut store = Store::open(":memory:").unwrap();
+        let ds = load("assets/AAPL_init_20210126.json");
+        let splits = ds.records.iter().filter(|r| r.split.is_some()).count() as i64;
+        let dividends = ds.records.iter().filter(|r| r.dividend.is_some()).count() as i64;
+        assert_eq!(store.upsert(&ds).unwrap(), ds.records.len());
+        assert_eq!(store.upsert(&ds).unwrap(), ds.records.len());
+        assert_eq!(count(&store, "symbols"), 1);
+        assert_eq!(count(&store, "bars"), ds.records.len() as i64);
+        assert_eq!(count(&store, "events"), splits + dividends);
+
+        let mut newer = load("assets/^GSPC_20210106_20210111.json");
+        store.upsert(&load("assets/^GSPC_20210104_20210108.json")).unwrap();
+        newer.records[1].close = Some(3804.0);
+        store.upsert(&newer).unwrap();
+        assert_eq!(count(&store, "symbols"), 2);
+        let (bars, close): (i64, f64) = store
+            .conn
+            .query_row(
+                "SELECT COUNT(*), MAX(CASE WHEN timestamp = 1610029800 THEN close END) FROM bars WHERE symbol = '^GSPC'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(bars, 6);
+        assert_eq!(close, 3804.0);
+    }
+
+    #[test]
+    fn test_upsert_intervals() {
+        // the weekly bars carry the dividend and split at the start of their week, the events
+        // table has them once at the time yahoo reports them
+        let mut store = Store::open(":memory:").unwrap();
+        let daily = load("assets/AAPL_init_20210126.json");
+        let weekly = load("assets/AAPL_1wk_20200803_20200904.json");
+        store.upsert(&daily).unwrap();
+        store.upsert(&weekly).unwrap();
+        assert_eq!(count(&store, "symbols"), 1);
+        assert_eq!(
+            count(&store, "bars"),
+            (daily.records.len() + weekly.records.len()) as i64
+        );
+        assert_eq!(count(&store, "events"), daily.events.len() as i64);
+        let split: (f64, String) = store
+            .conn
+            .query_row(
+                "SELECT amount, ratio FROM events WHERE timestamp = 1598880600 AND kind = 'split'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(split, (4.0, "4:1".to_string()));
+    }
+}
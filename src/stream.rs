@@ -0,0 +1,351 @@
+use crate::http::Result;
+use crate::options::StreamOpts as Opts;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use csv::WriterBuilder;
+use futures::{
+    sink::SinkExt,
+    stream::{self, Stream, StreamExt},
+};
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fs::OpenOptions,
+    io::{stdout, BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+use tokio::{net::TcpStream, time::sleep};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// The pricing message pushed by the yahoo streamer, only the fields we care about are decoded
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PricingData {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(float, tag = "2")]
+    pub price: f32,
+    /// milliseconds since epoch
+    #[prost(sint64, tag = "3")]
+    pub time: i64,
+    #[prost(string, tag = "4")]
+    pub currency: String,
+    #[prost(string, tag = "5")]
+    pub exchange: String,
+    #[prost(int32, tag = "6")]
+    pub quote_type: i32,
+    #[prost(int32, tag = "7")]
+    pub market_hours: i32,
+    #[prost(float, tag = "8")]
+    pub change_percent: f32,
+    #[prost(sint64, tag = "9")]
+    pub day_volume: i64,
+    #[prost(float, tag = "10")]
+    pub day_high: f32,
+    #[prost(float, tag = "11")]
+    pub day_low: f32,
+    #[prost(float, tag = "12")]
+    pub change: f32,
+}
+
+/// A price update for one symbol
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PriceTick {
+    pub symbol: String,
+    pub timestamp: DateTime<Utc>,
+    pub price: f64,
+    pub change: f64,
+    pub change_percent: f64,
+    pub day_volume: u64,
+    pub day_high: f64,
+    pub day_low: f64,
+    pub currency: String,
+    pub exchange: String,
+    pub market_hours: i32,
+}
+
+impl TryFrom<PricingData> for PriceTick {
+    type Error = String;
+
+    /// fails when the time is out of range
+    fn try_from(data: PricingData) -> std::result::Result<Self, Self::Error> {
+        let timestamp = DateTime::from_timestamp_millis(data.time)
+            .ok_or_else(|| format!("time {} of {} is out of range", data.time, data.id))?;
+        Ok(PriceTick {
+            symbol: data.id,
+            timestamp,
+            price: data.price as f64,
+            change: data.change as f64,
+            change_percent: data.change_percent as f64,
+            day_volume: data.day_volume.max(0) as u64,
+            day_high: data.day_high as f64,
+            day_low: data.day_low as f64,
+            currency: data.currency,
+            exchange: data.exchange,
+            market_hours: data.market_hours,
+        })
+    }
+}
+
+/// Newer streamers wrap the base64 payload in a JSON envelope
+#[derive(Deserialize, Debug)]
+struct Envelope {
+    message: String,
+}
+
+/// decode a text frame, either a bare base64 protobuf or a JSON envelope holding one
+pub fn decode_frame(text: &str) -> Result<PriceTick> {
+    let text = text.trim();
+    let payload = if text.starts_with('{') {
+        serde_json::from_str::<Envelope>(text)?.message
+    } else {
+        text.to_owned()
+    };
+    let bytes = STANDARD.decode(payload)?;
+    Ok(PriceTick::try_from(PricingData::decode(bytes.as_slice())?)?)
+}
+
+/// Where and how to connect to a streamer
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub url: String,
+    pub symbols: Vec<String>,
+    /// delay before the first reconnect, doubled on every failed attempt
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// give up after this many consecutive attempts that failed or were dropped before a message,
+    /// retry forever if `None`
+    pub max_retries: Option<u32>,
+}
+
+impl StreamConfig {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// the attempt count is kept while connected and only reset by a message, so that a server that
+/// accepts and hangs up right away backs off like one that refuses
+enum State {
+    Connecting(u32),
+    Connected(Box<Socket>, u32),
+}
+
+async fn connect(config: &StreamConfig) -> Result<Socket> {
+    let (mut socket, _) = connect_async(config.url.as_str()).await?;
+    let subscribe = serde_json::json!({ "subscribe": config.symbols });
+    socket.send(Message::Text(subscribe.to_string())).await?;
+    info!("subscribed to {:?} at {}", config.symbols, config.url);
+    Ok(socket)
+}
+
+/// Subscribe to a streamer and yield every decoded tick, reconnecting with exponential backoff
+/// whenever the connection drops. The stream ends only when `max_retries` is exhausted.
+pub fn subscribe(config: StreamConfig) -> impl Stream<Item = PriceTick> {
+    stream::unfold((State::Connecting(0), config), |(mut state, config)| async move {
+        loop {
+            state = match state {
+                State::Connecting(attempt) => {
+                    if config.max_retries.is_some_and(|max| attempt > max) {
+                        error!("giving up on {} after {} attempts", config.url, attempt - 1);
+                        return None;
+                    }
+                    if attempt > 0 {
+                        sleep(config.delay(attempt)).await;
+                    }
+                    match connect(&config).await {
+                        Ok(socket) => State::Connected(Box::new(socket), attempt),
+                        Err(err) => {
+                            warn!("failed to connect to {} with {:?}", config.url, err);
+                            State::Connecting(attempt + 1)
+                        }
+                    }
+                }
+                State::Connected(mut socket, attempt) => {
+                    let text = match socket.next().await {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Binary(bytes))) => String::from_utf8_lossy(&bytes).into_owned(),
+                        Some(Ok(Message::Close(_))) | None => {
+                            warn!("connection to {} closed", config.url);
+                            state = State::Connecting(attempt + 1);
+                            continue;
+                        }
+                        Some(Ok(_)) => {
+                            state = State::Connected(socket, attempt);
+                            continue;
+                        }
+                        Some(Err(err)) => {
+                            warn!("connection to {} failed with {:?}", config.url, err);
+                            state = State::Connecting(attempt + 1);
+                            continue;
+                        }
+                    };
+                    match decode_frame(&text) {
+                        Ok(tick) => return Some((tick, (State::Connected(socket, 0), config))),
+                        Err(err) => {
+                            warn!("failed to decode frame {:?} with {:?}", text, err);
+                            State::Connected(socket, 0)
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// write ticks as they arrive, flushing after each one so the output can be tailed
+async fn write_ticks<S, W>(ticks: S, writer: W, format: &str, header: bool) -> Result<usize>
+where
+    S: Stream<Item = PriceTick>,
+    W: Write,
+{
+    futures::pin_mut!(ticks);
+    let mut count = 0;
+    match format {
+        "csv" => {
+            let mut wtr = WriterBuilder::new().has_headers(header).from_writer(writer);
+            while let Some(tick) = ticks.next().await {
+                wtr.serialize(&tick)?;
+                wtr.flush()?;
+                count += 1;
+            }
+        }
+        _ => {
+            let mut writer = writer;
+            while let Some(tick) = ticks.next().await {
+                serde_json::to_writer(&mut writer, &tick)?;
+                writer.write_all(b"\n")?;
+                writer.flush()?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Entry function that takes a [crate::options::StreamOpts] and writes ticks to stdout or
+/// appends them to the output file
+pub async fn stream(opts: &Opts) -> Result<usize> {
+    let config = StreamConfig {
+        url: opts.url.clone(),
+        symbols: opts.symbols.clone(),
+        backoff: opts.backoff.0,
+        max_backoff: opts.max_backoff.0,
+        max_retries: opts.max_retries,
+    };
+    let ticks = subscribe(config).take(opts.limit.unwrap_or(usize::MAX));
+    match opts.output.as_ref() {
+        Some(path) => {
+            let header = Path::new(path).metadata().map_or(true, |m| m.len() == 0);
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            write_ticks(ticks, BufWriter::new(file), &opts.format, header).await
+        }
+        None => write_ticks(ticks, stdout(), &opts.format, true).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    fn load_frames() -> Vec<String> {
+        std::fs::read_to_string("assets/stream_frames_20210126.txt")
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    /// a local stand-in that replays `frames[i]` on the i-th connection and then hangs up
+    async fn serve(frames: Vec<Vec<String>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for batch in frames {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let mut ws = accept_async(tcp).await.unwrap();
+                let subscribe = ws.next().await.unwrap().unwrap();
+                assert!(subscribe.to_text().unwrap().contains("AAPL"));
+                for frame in batch {
+                    ws.send(Message::Text(frame)).await.unwrap();
+                }
+                let _ = ws.close(None).await;
+            }
+        });
+        format!("ws://{}", addr)
+    }
+
+    fn make_config(url: String) -> StreamConfig {
+        StreamConfig {
+            url,
+            symbols: vec!["AAPL".to_string(), "MSFT".to_string()],
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            max_retries: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let frames = load_frames();
+        let tick = decode_frame(&frames[0]).unwrap();
+        assert_eq!(tick.symbol, "AAPL");
+        assert_eq!(tick.price, 142.5);
+        assert_eq!(tick.day_volume, 1520345);
+        assert_eq!(tick.timestamp.timestamp_millis(), 1611671401000);
+        let wrapped = decode_frame(&frames[2]).unwrap();
+        assert_eq!(wrapped.price, 142.625);
+        assert!(decode_frame("not a frame").is_err());
+        let data = PricingData {
+            time: i64::MAX,
+            ..Default::default()
+        };
+        assert!(PriceTick::try_from(data).is_err());
+    }
+
+    #[test]
+    fn test_delay() {
+        let config = make_config("ws://localhost".to_string());
+        assert_eq!(config.delay(1), Duration::from_millis(10));
+        assert_eq!(config.delay(3), Duration::from_millis(40));
+        assert_eq!(config.delay(30), Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_reconnect() {
+        let frames = load_frames();
+        let url = serve(vec![frames[..2].to_vec(), frames[2..].to_vec()]).await;
+        let ticks: Vec<PriceTick> = subscribe(make_config(url)).collect().await;
+        let symbols: Vec<&str> = ticks.iter().map(|t| t.symbol.as_str()).collect();
+        assert_eq!(symbols, vec!["AAPL", "MSFT", "AAPL"]);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_hang_up() {
+        // a server that hangs up before any message counts as a failed attempt
+        let url = serve(vec![Vec::new(); 1000]).await;
+        let ticks = tokio::time::timeout(Duration::from_secs(5), subscribe(make_config(url)).collect::<Vec<_>>());
+        assert!(ticks.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_csv() {
+        let frames = load_frames();
+        let ticks = stream::iter(frames.iter().map(|f| decode_frame(f).unwrap()));
+        let mut buf = Vec::new();
+        let count = write_ticks(ticks, &mut buf, "csv", true).await.unwrap();
+        assert_eq!(count, 3);
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with("symbol,timestamp,price"));
+        assert_eq!(text.lines().count(), 4);
+    }
+}
@@ -1,49 +1,70 @@
-use chrono::{DateTime, FixedOffset, NaiveDateTime};
-use csv::Writer;
-use itertools::izip;
+use crate::dialect::{read_records, CsvSink};
+use crate::options::CsvArgs;
+use crate::panel::is_intraday;
+use crate::resample::Local;
+use crate::sidecar::{load_sidecar, sidecar_path};
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     error::Error,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufRead, BufReader, BufWriter, Write},
     path::Path,
 };
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct TradePeriod {
     pub timezone: String,
     pub start: u64,
     pub end: u64,
     pub gmtoffset: i32,
 }
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct CurrentTradePeriod {
     pub pre: TradePeriod,
     pub regular: TradePeriod,
     pub post: TradePeriod,
 }
-#[derive(Deserialize, Debug, Serialize)]
+/// Chart metadata. Yahoo omits different fields for indices, currencies, crypto, futures and
+/// intraday intervals so anything that is not always present is optional
+#[derive(Deserialize, Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct V8Meta {
-    pub currency: String,
+    pub currency: Option<String>,
     pub symbol: String,
+    #[serde(default)]
     pub exchange_name: String,
-    pub instrument_type: String,
-    pub first_trade_date: u64,
-    pub regular_market_time: u64,
+    pub instrument_type: Option<String>,
+    pub first_trade_date: Option<i64>,
+    pub regular_market_time: Option<i64>,
+    #[serde(default)]
     pub gmtoffset: i32,
+    #[serde(default)]
     pub timezone: String,
-    pub exchange_timezone_name: String,
-    pub regular_market_price: f32,
-    pub chart_previous_close: f32,
-    pub price_hint: f32,
-    pub current_trading_period: CurrentTradePeriod,
+    pub exchange_timezone_name: Option<String>,
+    pub regular_market_price: Option<f32>,
+    pub chart_previous_close: Option<f32>,
+    pub price_hint: Option<f32>,
+    pub current_trading_period: Option<CurrentTradePeriod>,
+    #[serde(default)]
     pub data_granularity: String,
+    #[serde(default)]
     pub range: String,
+    #[serde(default)]
     pub valid_ranges: Vec<String>,
 }
-#[derive(Deserialize, Debug)]
+
+impl V8Meta {
+    /// the fixed offset of the exchange when the chart was downloaded, UTC if it is out of range
+    pub fn offset(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.gmtoffset).unwrap_or_else(|| FixedOffset::east_opt(0).unwrap())
+    }
+}
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Deserialize, Debug, Default, Serialize)]
+#[serde(default)]
 pub struct OHLCV {
     pub volume: Vec<Option<u64>>,
     pub high: Vec<Option<f64>>,
@@ -51,56 +72,107 @@ pub struct OHLCV {
     pub low: Vec<Option<f64>>,
     pub open: Vec<Option<f64>>,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Serialize)]
 pub struct AdjClose {
+    #[serde(default)]
     pub adjclose: Vec<Option<f64>>,
 }
-#[derive(Deserialize, Debug)]
+/// `adjclose` is absent for intraday intervals
+#[derive(Deserialize, Debug, Serialize)]
 pub struct Indicators {
+    #[serde(default)]
     pub quote: Vec<OHLCV>,
+    #[serde(default)]
     pub adjclose: Vec<AdjClose>,
 }
 
-#[derive(Deserialize, Debug)]
+/// A stock split. Yahoo reports reverse and fractional splits such as `1:250` or `3:2` too, hence
+/// the floats
+#[derive(Deserialize, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Split {
     pub date: i64,
-    pub numerator: u8,
-    pub denominator: u8,
+    #[serde(default)]
+    pub numerator: f64,
+    #[serde(default)]
+    pub denominator: f64,
     pub split_ratio: String,
 }
 
-#[derive(Deserialize, Debug)]
+impl Split {
+    /// number of new shares per old share, e.g. 4 for `4:1` and 0.004 for `1:250`.
+    /// Falls back to parsing `split_ratio` when the numbers are missing
+    pub fn factor(&self) -> Option<f64> {
+        if self.numerator > 0.0 && self.denominator > 0.0 {
+            return Some(self.numerator / self.denominator);
+        }
+        ratio_factor(&self.split_ratio)
+    }
+}
+
+/// the factor of a split ratio such as `4:1` or `3/2`, the product for a list such as `4:1,2:1`
+fn ratio_factor(ratio: &str) -> Option<f64> {
+    ratio.split(',').try_fold(1.0, |product, ratio| {
+        let mut parts = ratio.split([':', '/']);
+        let num: f64 = parts.next()?.trim().parse().ok()?;
+        let den: f64 = parts.next()?.trim().parse().ok()?;
+        if num > 0.0 && den > 0.0 {
+            Some(product * num / den)
+        } else {
+            None
+        }
+    })
+}
+
+#[derive(Deserialize, Debug, Serialize)]
 pub struct Dividend {
     pub amount: f64,
     pub date: i64,
 }
 
-#[derive(Deserialize, Debug)]
+/// A capital gains distribution of a mutual fund or an ETF
+#[derive(Deserialize, Debug, Serialize)]
+pub struct CapitalGain {
+    pub amount: f64,
+    pub date: i64,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Event {
-    splits: Option<HashMap<String, Split>>,
-    dividends: Option<HashMap<String, Dividend>>,
+    pub splits: Option<HashMap<String, Split>>,
+    pub dividends: Option<HashMap<String, Dividend>>,
+    pub capital_gains: Option<HashMap<String, CapitalGain>>,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Serialize)]
 pub struct V8Result {
     pub meta: V8Meta,
+    /// absent when there is no bar in the requested range
+    #[serde(default)]
     pub timestamp: Vec<i64>,
     pub indicators: Indicators,
     pub events: Option<Event>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Serialize)]
+pub struct ChartError {
+    pub code: String,
+    pub description: String,
+}
+
+#[derive(Deserialize, Debug, Serialize)]
 pub struct Chart {
-    pub result: Vec<V8Result>,
-    pub error: Option<String>,
+    /// null when yahoo returns an error
+    pub result: Option<Vec<V8Result>>,
+    pub error: Option<ChartError>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Serialize)]
 pub struct ChartWrapper {
     pub chart: Chart,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Record {
     pub timestamp: DateTime<FixedOffset>,
     pub volume: Option<u64>,
@@ -109,77 +181,283 @@ pub struct Record {
     pub open: Option<f64>,
     pub close: Option<f64>,
     pub adjclose: Option<f64>,
+    /// the split ratio as reported, e.g. `4:1`
     pub split: Option<String>,
+    /// number of new shares per old share of a split on this bar
+    pub split_factor: Option<f64>,
     pub dividend: Option<f64>,
+    pub capital_gain: Option<f64>,
+    /// product of the split factors of all the later bars. Yahoo's prices are split-adjusted
+    /// already, multiply a price (divide a volume) by it to get the one as traded
+    pub cum_split_factor: f64,
 }
+/// A dividend, split or capital gain as reported, kept whether or not it lines up with a bar
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EventRecord {
+    pub timestamp: DateTime<FixedOffset>,
+    /// `dividend`, `capital_gain` or `split`
+    pub kind: &'static str,
+    /// the cash amount, or the number of new shares per old share of a split
+    pub amount: f64,
+    /// the split ratio as reported, e.g. `4:1`
+    pub ratio: Option<String>,
+}
+
 #[derive(Serialize, Debug)]
 pub struct DataSet {
     pub records: Vec<Record>,
+    /// every event in time order, see [DataSet::attach_events] for how they end up in records
+    pub events: Vec<EventRecord>,
     pub meta: V8Meta,
 }
 
+impl DataSet {
+    /// Attach every event to the bar whose period contains it, that is the last bar starting at or
+    /// before the event. Daily and longer bars are compared by exchange-local date as yahoo may
+    /// stamp an event at a different time of day than the bar. Events in the same bar are summed,
+    /// split factors multiplied, and events before the first bar are left out.
+    pub fn attach_events(&mut self) {
+        let local = Local::new(self);
+        let intraday = is_intraday(self);
+        let date = |t: &DateTime<FixedOffset>| local.to_local(&t.naive_utc()).date();
+        let dates: Vec<NaiveDate> = self.records.iter().map(|r| date(&r.timestamp)).collect();
+        for r in self.records.iter_mut() {
+            r.split = None;
+            r.split_factor = None;
+            r.dividend = None;
+            r.capital_gain = None;
+        }
+        for event in self.events.iter() {
+            let after = if intraday {
+                self.records.partition_point(|r| r.timestamp <= event.timestamp)
+            } else {
+                let event_date = date(&event.timestamp);
+                dates.partition_point(|d| *d <= event_date)
+            };
+            let r = match after.checked_sub(1) {
+                Some(i) => &mut self.records[i],
+                None => continue,
+            };
+            match event.kind {
+                "dividend" => r.dividend = Some(r.dividend.unwrap_or(0.0) + event.amount),
+                "capital_gain" => r.capital_gain = Some(r.capital_gain.unwrap_or(0.0) + event.amount),
+                _ => {
+                    let ratio = event.ratio.clone().unwrap_or_default();
+                    r.split = Some(match r.split.take() {
+                        Some(split) => format!("{},{}", split, ratio),
+                        None => ratio,
+                    });
+                    r.split_factor = Some(r.split_factor.unwrap_or(1.0) * event.amount);
+                }
+            }
+        }
+    }
+
+    /// Rebuild the events from the records they are attached to, for a dataset read back from an
+    /// output where only the in-record columns survive
+    pub fn collect_events(&mut self) {
+        let mut events = Vec::new();
+        for r in self.records.iter_mut() {
+            let event = |kind, amount, ratio| EventRecord {
+                timestamp: r.timestamp,
+                kind,
+                amount,
+                ratio,
+            };
+            if let Some(amount) = r.dividend {
+                events.push(event("dividend", amount, None));
+            }
+            if let Some(amount) = r.capital_gain {
+                events.push(event("capital_gain", amount, None));
+            }
+            if let Some(ratio) = r.split.clone() {
+                match r.split_factor.or_else(|| ratio_factor(&ratio)) {
+                    Some(factor) => {
+                        events.push(event("split", factor, Some(ratio)));
+                        r.split_factor = Some(factor);
+                    }
+                    None => warn!("skipping split {} of {} without a factor", ratio, self.meta.symbol),
+                }
+            }
+        }
+        events.sort_by(|a, b| (a.timestamp, a.kind).cmp(&(b.timestamp, b.kind)));
+        self.events = events;
+    }
+
+    /// walk backwards to fill in [Record::cum_split_factor], a split only affects the bars
+    /// before it as its own bar is already quoted post-split
+    pub fn accumulate_splits(&mut self) {
+        let mut cum = 1.0;
+        for r in self.records.iter_mut().rev() {
+            r.cum_split_factor = cum;
+            if let Some(factor) = r.split_factor {
+                cum *= factor;
+            }
+        }
+    }
+}
+
 /// convert chart to a vec of DataSet
 /// Usually there is only one element unless the symbol is ambiguious
 impl From<Chart> for Vec<DataSet> {
     fn from(chart: Chart) -> Self {
         let mut dataset_vec: Vec<DataSet> = vec![];
-        for result in chart.result.into_iter() {
-            let vohlca_iter = result
-                .indicators
-                .quote
-                .iter()
-                .zip(result.indicators.adjclose.iter())
-                .flat_map(|(ohlcv, adj)| {
-                    izip!(
-                        &ohlcv.volume,
-                        &ohlcv.open,
-                        &ohlcv.high,
-                        &ohlcv.low,
-                        &ohlcv.close,
-                        &adj.adjclose
-                    )
-                });
-            let gmtoffset = result.meta.gmtoffset;
+        for result in chart.result.unwrap_or_default().into_iter() {
+            // yahoo sends a single quote and adjclose per result, anything past it is not read
+            if result.indicators.quote.len() > 1 || result.indicators.adjclose.len() > 1 {
+                warn!(
+                    "{} has {} quotes and {} adjcloses, only the first of each is read",
+                    result.meta.symbol,
+                    result.indicators.quote.len(),
+                    result.indicators.adjclose.len()
+                );
+            }
+            let empty = OHLCV::default();
+            let quote = result.indicators.quote.first().unwrap_or(&empty);
+            let adjclose = result.indicators.adjclose.first().map(|a| &a.adjclose);
+            let offset = result.meta.offset();
+            let symbol = result.meta.symbol.clone();
+            // bars and events stamped out of chrono's range are dropped rather than put at 1970
+            let time = |t: i64| {
+                let tm = DateTime::from_timestamp(t, 0).map(|tm| tm.with_timezone(&offset));
+                if tm.is_none() {
+                    warn!("skipping timestamp {} of {} as it is out of range", t, symbol);
+                }
+                tm
+            };
             let mut ds = DataSet {
                 records: Vec::new(),
+                events: Vec::new(),
                 meta: result.meta,
             };
-            for (t, (v, o, h, l, c, a)) in result.timestamp.iter().zip(vohlca_iter) {
-                let naive = NaiveDateTime::from_timestamp(*t, 0);
-                let offset = FixedOffset::east(gmtoffset);
-                let tm = DateTime::<FixedOffset>::from_utc(naive, offset);
-                let (split, dividend) = result
-                    .events
-                    .as_ref()
-                    .map(|ev| {
-                        let date_key = t.to_string();
-                        (
-                            ev.splits
-                                .as_ref()
-                                .and_then(|m| m.get(&date_key).map(|s| s.split_ratio.clone())),
-                            ev.dividends.as_ref().and_then(|m| m.get(&date_key).map(|d| d.amount)),
-                        )
-                    })
-                    .unwrap_or((None, None));
-
+            for (i, t) in result.timestamp.iter().enumerate() {
+                let timestamp = match time(*t) {
+                    Some(timestamp) => timestamp,
+                    None => continue,
+                };
+                let get = |v: &Vec<Option<f64>>| v.get(i).copied().flatten();
                 ds.records.push(Record {
-                    timestamp: tm,
-                    volume: *v,
-                    high: *h,
-                    low: *l,
-                    open: *o,
-                    close: *c,
-                    adjclose: *a,
-                    split,
-                    dividend,
+                    timestamp,
+                    volume: quote.volume.get(i).copied().flatten(),
+                    high: get(&quote.high),
+                    low: get(&quote.low),
+                    open: get(&quote.open),
+                    close: get(&quote.close),
+                    adjclose: adjclose.and_then(get),
+                    split: None,
+                    split_factor: None,
+                    dividend: None,
+                    capital_gain: None,
+                    cum_split_factor: 1.0,
                 });
             }
+            if let Some(events) = result.events.as_ref() {
+                for d in events.dividends.iter().flat_map(|m| m.values()) {
+                    let timestamp = match time(d.date) {
+                        Some(timestamp) => timestamp,
+                        None => continue,
+                    };
+                    ds.events.push(EventRecord {
+                        timestamp,
+                        kind: "dividend",
+                        amount: d.amount,
+                        ratio: None,
+                    });
+                }
+                for c in events.capital_gains.iter().flat_map(|m| m.values()) {
+                    let timestamp = match time(c.date) {
+                        Some(timestamp) => timestamp,
+                        None => continue,
+                    };
+                    ds.events.push(EventRecord {
+                        timestamp,
+                        kind: "capital_gain",
+                        amount: c.amount,
+                        ratio: None,
+                    });
+                }
+                for s in events.splits.iter().flat_map(|m| m.values()) {
+                    let timestamp = match time(s.date) {
+                        Some(timestamp) => timestamp,
+                        None => continue,
+                    };
+                    if let Some(factor) = s.factor() {
+                        ds.events.push(EventRecord {
+                            timestamp,
+                            kind: "split",
+                            amount: factor,
+                            ratio: Some(s.split_ratio.clone()),
+                        });
+                    } else {
+                        warn!(
+                            "skipping split {} of {} without a factor",
+                            s.split_ratio, ds.meta.symbol
+                        );
+                    }
+                }
+            }
+            ds.events
+                .sort_by(|a, b| (a.timestamp, a.kind).cmp(&(b.timestamp, b.kind)));
+            ds.attach_events();
+            ds.accumulate_splits();
             dataset_vec.push(ds);
         }
         dataset_vec
     }
 }
 
+/// Back into the shape of a yahoo result so that datasets read from outputs go through the same
+/// paths as downloaded ones
+impl From<DataSet> for V8Result {
+    fn from(ds: DataSet) -> Self {
+        let mut quote = OHLCV::default();
+        let mut adjclose = Vec::new();
+        let timestamp = ds.records.iter().map(|r| r.timestamp.timestamp()).collect();
+        for r in ds.records.into_iter() {
+            quote.volume.push(r.volume);
+            quote.high.push(r.high);
+            quote.close.push(r.close);
+            quote.low.push(r.low);
+            quote.open.push(r.open);
+            adjclose.push(r.adjclose);
+        }
+        let (mut splits, mut dividends, mut capital_gains) = (HashMap::new(), HashMap::new(), HashMap::new());
+        for e in ds.events.into_iter() {
+            let date = e.timestamp.timestamp();
+            match e.kind {
+                "dividend" => {
+                    dividends.insert(date.to_string(), Dividend { amount: e.amount, date });
+                }
+                "capital_gain" => {
+                    capital_gains.insert(date.to_string(), CapitalGain { amount: e.amount, date });
+                }
+                _ => {
+                    let split = Split {
+                        date,
+                        numerator: e.amount,
+                        denominator: 1.0,
+                        split_ratio: e.ratio.unwrap_or_default(),
+                    };
+                    splits.insert(date.to_string(), split);
+                }
+            }
+        }
+        V8Result {
+            meta: ds.meta,
+            timestamp,
+            indicators: Indicators {
+                quote: vec![quote],
+                adjclose: vec![AdjClose { adjclose }],
+            },
+            events: Some(Event {
+                splits: Some(splits).filter(|m| !m.is_empty()),
+                dividends: Some(dividends).filter(|m| !m.is_empty()),
+                capital_gains: Some(capital_gains).filter(|m| !m.is_empty()),
+            }),
+        }
+    }
+}
+
 /// read a json from file
 pub fn load_from_json(path: &str) -> Result<ChartWrapper, Box<dyn Error>> {
     let file = File::open(path)?;
@@ -188,16 +466,114 @@ pub fn load_from_json(path: &str) -> Result<ChartWrapper, Box<dyn Error>> {
     Ok(u)
 }
 
-/// write a dataset to path
-pub fn write_to_csv<P: AsRef<Path>>(ds: &DataSet, path: P) -> Result<(), Box<dyn Error>> {
-    let file = File::create(path)?;
-    let writer = BufWriter::new(file);
-    let mut wtr = Writer::from_writer(writer);
-    for r in ds.records.iter() {
-        wtr.serialize(r)?;
+/// a dataset with the meta of the sidecar next to `path` and no records yet
+fn from_sidecar(path: &Path) -> Result<DataSet, Box<dyn Error>> {
+    let sidecar = load_sidecar(sidecar_path(path))
+        .map_err(|err| format!("failed to load the sidecar of {:?} with {}", path, err))?;
+    Ok(DataSet {
+        records: Vec::new(),
+        events: Vec::new(),
+        meta: sidecar.meta,
+    })
+}
+
+/// Read a csv in the dialect of `args` back into a dataset, the counterpart of [write_to_csv].
+/// The meta comes from the `.meta.json` sidecar next to it and the events from the split,
+/// dividend and capital gain columns
+pub fn load_from_csv<P: AsRef<Path>>(path: P, args: &CsvArgs) -> Result<DataSet, Box<dyn Error>> {
+    let mut ds = from_sidecar(path.as_ref())?;
+    ds.records = read_records(BufReader::new(File::open(path)?), args, &ds)?;
+    ds.collect_events();
+    ds.accumulate_splits();
+    Ok(ds)
+}
+
+/// Read json lines back into a dataset, the counterpart of [write_to_jsonl]. The meta comes from
+/// the `.meta.json` sidecar next to it
+pub fn load_from_jsonl<P: AsRef<Path>>(path: P) -> Result<DataSet, Box<dyn Error>> {
+    let mut ds = from_sidecar(path.as_ref())?;
+    let offset = ds.meta.offset();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut record: Record = serde_json::from_str(&line)?;
+        record.timestamp = record.timestamp.with_timezone(&offset);
+        ds.records.push(record);
     }
+    ds.collect_events();
+    ds.accumulate_splits();
+    Ok(ds)
+}
+
+/// Load a yahoo json, or a csv or json lines output with its sidecar, by the extension of `path`
+pub fn load_chart(path: &Path, args: &CsvArgs) -> Result<ChartWrapper, Box<dyn Error>> {
+    let ds = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => load_from_csv(path, args)?,
+        Some("jsonl") => load_from_jsonl(path)?,
+        _ => return load_from_json(path.to_str().unwrap()),
+    };
+    Ok(ChartWrapper {
+        chart: Chart {
+            result: Some(vec![ds.into()]),
+            error: None,
+        },
+    })
+}
+
+/// write a dataset to path in the csv dialect of `args`
+pub fn write_to_csv<P: AsRef<Path>>(ds: &DataSet, path: P, args: &CsvArgs) -> Result<(), Box<dyn Error>> {
+    let mut sink = CsvSink::new(BufWriter::new(File::create(path)?), args.clone(), false);
+    sink.write(ds)?;
+    sink.close()
+}
+/// The series a record belongs to, written in front of it when many symbols share one output
+#[derive(Serialize, Debug)]
+pub struct Tags<'a> {
+    pub symbol: &'a str,
+    pub exchange: &'a str,
+    pub currency: &'a str,
+    pub interval: &'a str,
+}
+
+impl DataSet {
+    /// the [Tags] of every record of this dataset
+    pub fn tags(&self) -> Tags<'_> {
+        Tags {
+            symbol: &self.meta.symbol,
+            exchange: &self.meta.exchange_name,
+            currency: self.meta.currency.as_deref().unwrap_or(""),
+            interval: &self.meta.data_granularity,
+        }
+    }
+}
+
+/// a record with its [Tags] flattened into one JSON object
+#[derive(Serialize)]
+struct TaggedRecord<'a> {
+    #[serde(flatten)]
+    tags: &'a Tags<'a>,
+    #[serde(flatten)]
+    record: &'a Record,
+}
+
+/// Append a dataset as JSON lines, one object per record with its [Tags]
+pub fn write_tagged_jsonl<W: Write>(ds: &DataSet, writer: &mut W) -> Result<(), Box<dyn Error>> {
+    let tags = ds.tags();
+    for record in ds.records.iter() {
+        serde_json::to_writer(&mut *writer, &TaggedRecord { tags: &tags, record })?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
     Ok(())
 }
+
+/// write a dataset to path as JSON lines
+pub fn write_to_jsonl<P: AsRef<Path>>(ds: &DataSet, path: P) -> Result<(), Box<dyn Error>> {
+    write_tagged_jsonl(ds, &mut BufWriter::new(File::create(path)?))
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::remove_file;
@@ -205,6 +581,7 @@ mod tests {
     use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
     use super::*;
+    use crate::sidecar::{write_sidecar, Sidecar};
 
     #[test]
     fn test_load_json() {
@@ -220,6 +597,97 @@ mod tests {
         let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
         assert_eq!(ds_vec.len(), 1);
         assert_eq!(ds_vec[0].records.len(), 3);
+
+        // a bar out of chrono's range is dropped instead of being put at 1970
+        let mut chart_wrapper = load_from_json("assets/GXY.AX_20200103_20200107.json").unwrap();
+        chart_wrapper.chart.result.as_mut().unwrap()[0].timestamp[1] = i64::MAX;
+        let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+        assert_eq!(ds_vec[0].records.len(), 2);
+        assert!(ds_vec[0].records.iter().all(|r| r.timestamp.timestamp() > 0));
+    }
+
+    #[test]
+    fn test_instrument_types() {
+        for (path, count) in [
+            ("assets/^GSPC_20210104_20210108.json", 4),
+            ("assets/AUDUSD=X_20210104_20210106.json", 2),
+            ("assets/BTC-USD_20210104_20210106.json", 2),
+            ("assets/ES=F_20210104_20210106.json", 3),
+            ("assets/AAPL_5m_20210125.json", 4),
+            ("assets/A2M.AX_20200104_20200105.json", 0),
+        ]
+        .iter()
+        {
+            let chart_wrapper = load_from_json(path).unwrap_or_else(|e| panic!("failed to load {} with {:?}", path, e));
+            let ds_vec: Vec<DataSet> = chart_wrapper.chart.into();
+            assert_eq!(ds_vec.len(), 1);
+            assert_eq!(ds_vec[0].records.len(), *count, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_optional_fields() {
+        let ds_vec: Vec<DataSet> = load_from_json("assets/^GSPC_20210104_20210108.json")
+            .unwrap()
+            .chart
+            .into();
+        assert_eq!(ds_vec[0].meta.first_trade_date, Some(-1325583000));
+
+        let ds_vec: Vec<DataSet> = load_from_json("assets/AAPL_5m_20210125.json").unwrap().chart.into();
+        assert!(ds_vec[0].records.iter().all(|r| r.adjclose.is_none()));
+        assert_eq!(ds_vec[0].records[1].close, Some(141.6199951171875));
+        assert_eq!(ds_vec[0].records[2].volume, None);
+
+        let ds_vec: Vec<DataSet> = load_from_json("assets/ES=F_20210104_20210106.json")
+            .unwrap()
+            .chart
+            .into();
+        assert!(ds_vec[0].meta.current_trading_period.is_none());
+
+        let chart = load_from_json("assets/NOTFOUND_init_20210126.json").unwrap().chart;
+        assert_eq!(chart.error.as_ref().unwrap().code, "Not Found");
+        let ds_vec: Vec<DataSet> = chart.into();
+        assert!(ds_vec.is_empty());
+    }
+
+    #[test]
+    fn test_split_factor() {
+        let ds_vec: Vec<DataSet> = load_from_json("assets/AAPL_init_20210126.json").unwrap().chart.into();
+        let records = &ds_vec[0].records;
+        assert_eq!(records.iter().filter(|r| r.split.is_some()).count(), 5);
+        assert_eq!(records[0].cum_split_factor, 224.0);
+        assert_eq!(records.last().unwrap().cum_split_factor, 1.0);
+        let split = records.iter().find(|r| r.split.as_deref() == Some("4:1")).unwrap();
+        assert_eq!(split.split_factor, Some(4.0));
+        assert_eq!(split.cum_split_factor, 1.0);
+    }
+
+    #[test]
+    fn test_reverse_and_fractional_splits() {
+        let ds_vec: Vec<DataSet> = load_from_json("assets/XYZ_splits_20210104_20210108.json")
+            .unwrap()
+            .chart
+            .into();
+        let factors: Vec<Option<f64>> = ds_vec[0].records.iter().map(|r| r.split_factor).collect();
+        assert_eq!(factors, vec![None, Some(1.5), None, Some(0.004), Some(2.0)]);
+        let cum: Vec<f64> = ds_vec[0].records.iter().map(|r| r.cum_split_factor).collect();
+        assert_eq!(cum, vec![0.012, 0.008, 0.008, 2.0, 1.0]);
+
+        // some splits only come with the ratio
+        let split: Split = serde_json::from_str(r#"{"date": 1609770600, "splitRatio": "3:2"}"#).unwrap();
+        assert_eq!(split.factor(), Some(1.5));
+    }
+
+    #[test]
+    fn test_capital_gains() {
+        let ds_vec: Vec<DataSet> = load_from_json("assets/VFIAX_20201214_20201218.json")
+            .unwrap()
+            .chart
+            .into();
+        let records = &ds_vec[0].records;
+        assert_eq!(records[2].capital_gain, Some(0.184));
+        assert_eq!(records[2].dividend, Some(1.3218));
+        assert_eq!(records.iter().filter(|r| r.capital_gain.is_some()).count(), 1);
     }
 
     #[test]
@@ -232,8 +700,98 @@ mod tests {
             .map(char::from)
             .collect();
         let path = std::env::temp_dir().join(prefix);
-        let result = write_to_csv(&ds_vec[0], path.clone());
+        let result = write_to_csv(&ds_vec[0], path.clone(), &CsvArgs::default());
         assert!(result.is_ok());
         let _ = remove_file(path);
     }
+
+    #[test]
+    fn test_write_tagged_csv() {
+        let mut buf = Vec::new();
+        let mut sink = CsvSink::new(&mut buf, CsvArgs::default(), true);
+        for path in [
+            "assets/^GSPC_20210104_20210108.json",
+            "assets/BTC-USD_20210104_20210106.json",
+        ]
+        .iter()
+        {
+            let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+            sink.write(&ds_vec[0]).unwrap();
+        }
+        sink.close().unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert!(lines[0].starts_with("symbol,exchange,currency,interval,timestamp,volume,"));
+        assert!(lines[1].starts_with("^GSPC,SNP,USD,1d,2021-01-04T09:30:00-05:00,"));
+        assert!(lines[6].starts_with("BTC-USD,CCC,USD,1d,"));
+    }
+
+    #[test]
+    fn test_write_tagged_jsonl() {
+        let ds_vec: Vec<DataSet> = load_from_json("assets/^GSPC_20210104_20210108.json")
+            .unwrap()
+            .chart
+            .into();
+        let mut buf = Vec::new();
+        write_tagged_jsonl(&ds_vec[0], &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<serde_json::Value> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), ds_vec[0].records.len());
+        assert_eq!(lines[0]["symbol"], "^GSPC");
+        assert_eq!(lines[0]["interval"], "1d");
+        assert_eq!(lines[0]["timestamp"], "2021-01-04T09:30:00-05:00");
+        assert_eq!(lines[0]["close"], ds_vec[0].records[0].close.unwrap());
+    }
+
+    #[test]
+    fn test_load_from_csv() {
+        // events come back at the bar they are attached to
+        let as_json = |ds: &DataSet| {
+            let events: Vec<_> = ds.events.iter().map(|e| (e.kind, e.amount, &e.ratio)).collect();
+            serde_json::to_string(&(&ds.records, events)).unwrap()
+        };
+        let prefix: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(30)
+            .map(char::from)
+            .collect();
+        let dir = std::env::temp_dir();
+        let (csv, jsonl) = (dir.join(prefix.clone() + ".csv"), dir.join(prefix.clone() + ".jsonl"));
+        let sidecars = [sidecar_path(&csv), sidecar_path(&jsonl)];
+        assert!(load_from_csv(&csv, &CsvArgs::default()).is_err());
+
+        for path in [
+            "assets/AAPL_init_20210126.json",
+            "assets/AAPL_1wk_20200803_20200904.json",
+        ]
+        .iter()
+        {
+            let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+            let ds = &ds_vec[0];
+            write_to_csv(ds, &csv, &CsvArgs::default()).unwrap();
+            write_to_jsonl(ds, &jsonl).unwrap();
+            for sidecar in sidecars.iter() {
+                write_sidecar(&Sidecar::new(ds, Path::new(path)), sidecar).unwrap();
+            }
+
+            let loaded = load_from_csv(&csv, &CsvArgs::default()).unwrap();
+            assert_eq!(loaded.meta.symbol, "AAPL");
+            assert_eq!(as_json(&loaded), as_json(ds));
+            assert_eq!(as_json(&load_from_jsonl(&jsonl).unwrap()), as_json(ds));
+
+            // through the shape of a yahoo result as every subcommand reads it
+            let ds_vec: Vec<DataSet> = load_chart(&csv, &CsvArgs::default()).unwrap().chart.into();
+            assert_eq!(as_json(&ds_vec[0]), as_json(ds));
+            let to = "1mo".parse().unwrap();
+            let resampled = serde_json::to_string(&ds_vec.into_iter().next().unwrap().resample(to).unwrap().records);
+            assert_eq!(
+                resampled.unwrap(),
+                serde_json::to_string(&loaded.resample(to).unwrap().records).unwrap()
+            );
+        }
+        for path in [csv, jsonl].iter().chain(sidecars.iter()) {
+            let _ = remove_file(path);
+        }
+    }
 }
@@ -0,0 +1,205 @@
+use crate::v8chart::{DataSet, Record};
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fs::read_to_string, path::Path};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// the rule is disabled
+    Off,
+    Info,
+    Warning,
+    Error,
+}
+
+/// The rules to check a series against. A rule is disabled by setting its severity to `off`
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// a timestamp that is the same as or earlier than the one before it
+    pub duplicate_timestamp: Severity,
+    /// a bar without any price or volume
+    pub empty_bar: Severity,
+    /// high below low, or open/close outside of the high-low range
+    pub inverted_range: Severity,
+    /// a bar with zero volume, only checked when the series has any volume at all
+    pub zero_volume: Severity,
+    /// a single bar that is `spike_ratio` times above or below both of its neighbours
+    pub spike: Severity,
+    pub spike_ratio: f64,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            duplicate_timestamp: Severity::Error,
+            empty_bar: Severity::Warning,
+            inverted_range: Severity::Error,
+            zero_volume: Severity::Warning,
+            spike: Severity::Error,
+            spike_ratio: 100.0,
+        }
+    }
+}
+
+/// read rules from a JSON or TOML file, decided by the extension
+pub fn load_rules<P: AsRef<Path>>(path: P) -> Result<Rules, Box<dyn Error>> {
+    let path = path.as_ref();
+    let content = read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => Ok(toml::from_str(&content)?),
+        _ => Ok(serde_json::from_str(&content)?),
+    }
+}
+
+/// A bar that breaks a rule
+#[derive(Serialize, Debug)]
+pub struct Violation {
+    pub symbol: String,
+    pub timestamp: DateTime<FixedOffset>,
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+fn prices(r: &Record) -> [Option<f64>; 4] {
+    [r.open, r.high, r.low, r.close]
+}
+
+/// whether `p` is `ratio` times above or below both neighbours
+fn is_spike(p: f64, prev: Option<f64>, next: Option<f64>, ratio: f64) -> bool {
+    match (prev, next) {
+        (Some(a), Some(b)) if a > 0.0 && b > 0.0 && p > 0.0 => {
+            (p / a > ratio && p / b > ratio) || (a / p > ratio && b / p > ratio)
+        }
+        _ => false,
+    }
+}
+
+impl DataSet {
+    /// check every bar against `rules` and return the violations in the order of the bars
+    pub fn validate(&self, rules: &Rules) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut report = |r: &Record, rule: &'static str, severity: Severity, message: String| {
+            if severity != Severity::Off {
+                violations.push(Violation {
+                    symbol: self.meta.symbol.clone(),
+                    timestamp: r.timestamp,
+                    rule,
+                    severity,
+                    message,
+                });
+            }
+        };
+        let has_volume = self.records.iter().any(|r| r.volume.unwrap_or(0) > 0);
+        let closes: Vec<Option<f64>> = self.records.iter().map(|r| r.close).collect();
+
+        for (i, r) in self.records.iter().enumerate() {
+            if i > 0 && r.timestamp <= self.records[i - 1].timestamp {
+                let message = format!("follows {}", self.records[i - 1].timestamp);
+                report(r, "duplicate_timestamp", rules.duplicate_timestamp, message);
+            }
+            if prices(r).iter().all(Option::is_none) && r.volume.is_none() {
+                report(r, "empty_bar", rules.empty_bar, "all values are null".to_string());
+                continue;
+            }
+            if let (Some(high), Some(low)) = (r.high, r.low) {
+                let outside = [r.open, r.close].iter().flatten().any(|p| *p > high || *p < low);
+                if high < low || outside {
+                    let message = format!("high {} low {} open {:?} close {:?}", high, low, r.open, r.close);
+                    report(r, "inverted_range", rules.inverted_range, message);
+                }
+            }
+            if has_volume && r.volume == Some(0) {
+                report(r, "zero_volume", rules.zero_volume, "no volume traded".to_string());
+            }
+            let prev = closes[..i].iter().rev().find_map(|c| *c);
+            let next = closes.get(i + 1..).and_then(|c| c.iter().find_map(|c| *c));
+            if let Some(p) = prices(r)
+                .iter()
+                .flatten()
+                .find(|p| is_spike(**p, prev, next, rules.spike_ratio))
+            {
+                let message = format!("{} against neighbours {:?} and {:?}", p, prev, next);
+                report(r, "spike", rules.spike, message);
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    fn rules_of(violations: &[Violation]) -> Vec<&str> {
+        violations.iter().map(|v| v.rule).collect()
+    }
+
+    #[test]
+    fn test_clean_series() {
+        let ds = load("assets/AAPL_init_20210126.json");
+        let violations = ds.validate(&Rules::default());
+        assert!(
+            violations.iter().all(|v| v.severity < Severity::Error),
+            "{:?}",
+            violations
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        let mut ds = load("assets/^GSPC_20210104_20210108.json");
+        ds.records[1].timestamp = ds.records[0].timestamp;
+        ds.records[2].high = Some(3000.0);
+        ds.records[3].volume = Some(0);
+        let violations = ds.validate(&Rules::default());
+        assert_eq!(
+            rules_of(&violations),
+            vec!["duplicate_timestamp", "inverted_range", "zero_volume"]
+        );
+        assert_eq!(violations[2].severity, Severity::Warning);
+
+        let ds = load("assets/ES=F_20210104_20210106.json");
+        assert_eq!(rules_of(&ds.validate(&Rules::default())), vec!["empty_bar"]);
+    }
+
+    #[test]
+    fn test_spike() {
+        let mut ds = load("assets/^GSPC_20210104_20210108.json");
+        let r = &mut ds.records[2];
+        r.scale(150.0);
+        let violations = ds.validate(&Rules::default());
+        assert_eq!(rules_of(&violations), vec!["spike"]);
+
+        let rules: Rules = toml::from_str("spike_ratio = 1000.0\nduplicate_timestamp = \"off\"").unwrap();
+        assert_eq!(rules.duplicate_timestamp, Severity::Off);
+        assert_eq!(rules.zero_volume, Severity::Warning);
+        assert!(ds.validate(&rules).is_empty());
+    }
+
+    #[test]
+    fn test_no_volume_series() {
+        let ds = load("assets/AUDUSD=X_20210104_20210106.json");
+        assert!(ds.validate(&Rules::default()).is_empty());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules: Rules = serde_json::from_str(r#"{"spike": "off", "spike_ratio": 5}"#).unwrap();
+        assert_eq!(rules.spike, Severity::Off);
+        assert_eq!(rules.spike_ratio, 5.0);
+        assert_eq!(rules.empty_bar, Severity::Warning);
+        // a misspelt rule is an error rather than silently left at its default
+        assert!(serde_json::from_str::<Rules>(r#"{"spike_ration": 5}"#).is_err());
+        assert!(toml::from_str::<Rules>("zero_volumes = \"off\"").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::panel::is_intraday;
+use crate::resample::Local;
+use crate::v8chart::DataSet;
+
+use chrono::DateTime;
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use std::{collections::HashSet, error::Error, fs::File, io::Write, path::Path};
+
+/// Columns of a symbol sheet
+const COLUMNS: [&str; 10] = [
+    "Date",
+    "Open",
+    "High",
+    "Low",
+    "Close",
+    "Adj Close",
+    "Volume",
+    "Dividend",
+    "Split",
+    "Capital Gain",
+];
+
+/// Columns of the summary sheet, a row per dataset
+const SUMMARY: [&str; 10] = [
+    "Symbol",
+    "Sheet",
+    "Currency",
+    "Exchange",
+    "Instrument Type",
+    "Timezone",
+    "Interval",
+    "First Trade Date",
+    "Last Price",
+    "Bars",
+];
+
+/// A number format with as many decimals as the price hint of a symbol, 2 when it is missing
+fn price_format(price_hint: Option<f32>) -> Format {
+    let decimals = price_hint.map_or(2, |h| h.max(0.0) as usize);
+    let num_format = if decimals == 0 {
+        "#,##0".to_string()
+    } else {
+        format!("#,##0.{}", "0".repeat(decimals))
+    };
+    Format::new().set_num_format(num_format)
+}
+
+/// A unique, valid sheet name for a symbol: at most 31 characters and none of `*?:[]\/`
+fn sheet_name(symbol: &str, taken: &HashSet<String>) -> String {
+    let mut base: String = symbol
+        .chars()
+        .map(|c| if "*?:[]\\/".contains(c) { '_' } else { c })
+        .take(31)
+        .collect();
+    base = base.trim_matches('\'').to_string();
+    if base.is_empty() {
+        base = "Sheet".to_string();
+    }
+    let mut name = base.clone();
+    let mut i = 2;
+    while taken.contains(&name.to_lowercase()) {
+        let suffix = format!("_{}", i);
+        name = base.chars().take(31 - suffix.len()).collect::<String>() + &suffix;
+        i += 1;
+    }
+    name
+}
+
+/// A workbook with a summary sheet first and then a sheet per dataset. Nothing is written to the
+/// underlying writer until [XlsxSink::close] as a workbook is a zip archive
+pub struct XlsxSink<W: Write> {
+    writer: W,
+    summary: Worksheet,
+    sheets: Vec<Worksheet>,
+    taken: HashSet<String>,
+    header: Format,
+}
+
+impl<W: Write> XlsxSink<W> {
+    pub fn new(writer: W) -> Result<XlsxSink<W>, XlsxError> {
+        let header = Format::new().set_bold();
+        let mut summary = Worksheet::new();
+        summary.set_name("Summary")?;
+        summary.write_row_with_format(0, 0, SUMMARY, &header)?;
+        summary.set_freeze_panes(1, 0)?;
+        summary.set_column_width(7, 16)?;
+        let mut taken = HashSet::new();
+        taken.insert("summary".to_string());
+        Ok(XlsxSink {
+            writer,
+            summary,
+            sheets: Vec::new(),
+            taken,
+            header,
+        })
+    }
+
+    /// add a sheet for the dataset and a row for its meta to the summary
+    pub fn write(&mut self, ds: &DataSet) -> Result<(), Box<dyn Error>> {
+        let meta = &ds.meta;
+        let name = sheet_name(&meta.symbol, &self.taken);
+        self.taken.insert(name.to_lowercase());
+        let local = Local::new(ds);
+        let price = price_format(meta.price_hint);
+        let (date, width) = if is_intraday(ds) {
+            (Format::new().set_num_format("yyyy-mm-dd hh:mm"), 17)
+        } else {
+            (Format::new().set_num_format("yyyy-mm-dd"), 11)
+        };
+        let volume = Format::new().set_num_format("#,##0");
+
+        let mut sheet = Worksheet::new();
+        sheet.set_name(&name)?;
+        sheet.write_row_with_format(0, 0, COLUMNS, &self.header)?;
+        sheet.set_freeze_panes(1, 0)?;
+        sheet.set_column_width(0, width)?;
+        sheet.set_column_width(6, 15)?;
+        for (i, r) in ds.records.iter().enumerate() {
+            let row = i as u32 + 1;
+            sheet.write_datetime_with_format(row, 0, local.to_local(&r.timestamp.naive_utc()), &date)?;
+            for (col, value) in [r.open, r.high, r.low, r.close, r.adjclose].iter().enumerate() {
+                if let Some(value) = value {
+                    sheet.write_number_with_format(row, col as u16 + 1, *value, &price)?;
+                }
+            }
+            if let Some(v) = r.volume {
+                sheet.write_number_with_format(row, 6, v as f64, &volume)?;
+            }
+            if let Some(dividend) = r.dividend {
+                sheet.write_number(row, 7, dividend)?;
+            }
+            if let Some(split) = r.split.as_ref() {
+                sheet.write_string(row, 8, split)?;
+            }
+            if let Some(capital_gain) = r.capital_gain {
+                sheet.write_number(row, 9, capital_gain)?;
+            }
+        }
+        self.sheets.push(sheet);
+
+        let row = self.sheets.len() as u32;
+        let summary = &mut self.summary;
+        summary.write_string(row, 0, &meta.symbol)?;
+        summary.write_string(row, 1, &name)?;
+        summary.write_string(row, 2, meta.currency.as_deref().unwrap_or(""))?;
+        summary.write_string(row, 3, &meta.exchange_name)?;
+        summary.write_string(row, 4, meta.instrument_type.as_deref().unwrap_or(""))?;
+        summary.write_string(row, 5, meta.exchange_timezone_name.as_deref().unwrap_or(&meta.timezone))?;
+        summary.write_string(row, 6, &meta.data_granularity)?;
+        if let Some(first) = meta.first_trade_date.and_then(|t| DateTime::from_timestamp(t, 0)) {
+            let first = local.to_local(&first.naive_utc()).date();
+            summary.write_date_with_format(row, 7, first, &Format::new().set_num_format("yyyy-mm-dd"))?;
+        }
+        if let Some(last) = meta.regular_market_price {
+            summary.write_number_with_format(row, 8, last as f64, &price)?;
+        }
+        summary.write_number(row, 9, ds.records.len() as f64)?;
+        Ok(())
+    }
+
+    /// assemble the workbook and write it out
+    pub fn close(mut self) -> Result<(), Box<dyn Error>> {
+        let mut workbook = Workbook::new();
+        workbook.push_worksheet(self.summary);
+        for sheet in self.sheets {
+            workbook.push_worksheet(sheet);
+        }
+        self.writer.write_all(&workbook.save_to_buffer()?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// write a dataset to path as a workbook with a summary sheet and a sheet of bars
+pub fn write_to_xlsx<P: AsRef<Path>>(ds: &DataSet, path: P) -> Result<(), Box<dyn Error>> {
+    let mut sink = XlsxSink::new(File::create(path)?)?;
+    sink.write(ds)?;
+    sink.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v8chart::load_from_json;
+
+    fn load(path: &str) -> DataSet {
+        let ds_vec: Vec<DataSet> = load_from_json(path).unwrap().chart.into();
+        ds_vec.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_sheet_name() {
+        let mut taken = HashSet::new();
+        assert_eq!(sheet_name("^GSPC", &taken), "^GSPC");
+        assert_eq!(sheet_name("a/b:c", &taken), "a_b_c");
+        taken.insert("summary".to_string());
+        assert_eq!(sheet_name("SUMMARY", &taken), "SUMMARY_2");
+        let long = "X".repeat(40);
+        taken.insert("x".repeat(31));
+        assert_eq!(sheet_name(&long, &taken), format!("{}_2", "X".repeat(29)));
+        assert_eq!(price_format(Some(4.0)), Format::new().set_num_format("#,##0.0000"));
+        assert_eq!(price_format(Some(0.0)), Format::new().set_num_format("#,##0"));
+    }
+
+    #[test]
+    fn test_xlsx_sink() {
+        let mut buf = Vec::new();
+        let mut sink = XlsxSink::new(&mut buf).unwrap();
+        for path in [
+            "assets/^GSPC_20210104_20210108.json",
+            "assets/AAPL_5m_20210125.json",
+            "assets/AUDUSD=X_20210104_20210106.json",
+        ]
+        .iter()
+        {
+            sink.write(&load(path)).unwrap();
+        }
+        assert_eq!(sink.sheets.len(), 3);
+        let names: Vec<String> = sink.sheets.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["^GSPC", "AAPL", "AUDUSD=X"]);
+        sink.close().unwrap();
+        assert!(buf.starts_with(b"PK"));
+    }
+}